@@ -1,9 +1,33 @@
-use crate::db::{User, DB};
-use crate::utils::{find_user_by_username, send_message, State};
+use crate::db::{DbError, InviteRedemption, User, UserRole, DB};
+use crate::gpt;
+use crate::tts::{self, Voice};
+use crate::utils::{
+    authorize_group_member, authorize_user, conversation_for, default_contact_form, find_group,
+    find_user, refresh_groups, refresh_users, report_db_error, send_message, send_thread_message,
+    topic_id, State,
+};
+use rusqlite::types::Value;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
 use teloxide::{prelude::*, utils::command::BotCommands};
 
+const PERMISSION_DENIED: &str = "You don't have permission to use this command";
+const LAST_ADMIN: &str = "This is the last admin, make someone else an admin first";
+
+const DEFAULT_INVITE_USES: u32 = 1;
+const DEFAULT_INVITE_TTL: &str = "24h";
+/// Invites can't be valid for longer than a year.
+const MAX_INVITE_TTL_SECONDS: i64 = 365 * 24 * 60 * 60;
+
+/// Prefix of the /voicecfg keyboard callback data.
+const VOICECFG_PREFIX: &str = "voicecfg:";
+/// Telegram limits callback data to 64 bytes.
+const CALLBACK_DATA_LIMIT: usize = 64;
+/// Telegram allows at most 100 buttons, a few are left for the rate row.
+const MAX_VOICE_BUTTONS: usize = 90;
+const RATE_OPTIONS: [f32; 4] = [0.75, 1.0, 1.25, 1.5];
+
 #[derive(BotCommands, Clone)]
 #[command(
     rename_rule = "lowercase",
@@ -18,8 +42,47 @@ enum Command {
     Text,
     #[command(description = "Voice responses")]
     Voice,
+    #[command(description = "Toggle echo of recognized voice messages")]
+    Echo,
+    #[command(description = "Toggle transcription only mode (no GPT answers)")]
+    Transcribe,
+    #[command(description = "Show or change GPT model, /model default resets it")]
+    Model,
+    #[command(
+        description = "Show or change temperature (0.0 - 2.0), /temperature default resets it"
+    )]
+    Temperature,
+    #[command(description = "List personas or switch to another one")]
+    Persona,
+    #[command(description = "Choose TTS voice and speech rate")]
+    VoiceCfg,
+}
+
+#[derive(BotCommands, Clone)]
+#[command(rename_rule = "lowercase", description = "Admin commands:")]
+enum AdminCommand {
     #[command(description = "Broadcast message")]
     Broadcast,
+    #[command(description = "Add user: /adduser <username> <contact_name> <contact_form>")]
+    AddUser,
+    #[command(description = "Remove user: /removeuser <username|telegram_id>")]
+    RemoveUser,
+    #[command(description = "List users")]
+    ListUsers,
+    #[command(
+        description = "Change user setting: /setuser <username|telegram_id> <field> <value>"
+    )]
+    SetUser,
+    #[command(description = "Create invite link: /invite [uses] [ttl, e.g. 30m, 12h, 7d]")]
+    Invite,
+    #[command(description = "Authorize the group this command is sent in")]
+    AddGroup,
+    #[command(description = "Remove group: /removegroup [chat_id]")]
+    RemoveGroup,
+    #[command(description = "List authorized groups")]
+    ListGroups,
+    #[command(description = "Group history mode: /groupmode <shared|member>")]
+    GroupMode,
 }
 
 impl FromStr for Command {
@@ -31,7 +94,41 @@ impl FromStr for Command {
             "new" => Ok(Command::New),
             "text" => Ok(Command::Text),
             "voice" => Ok(Command::Voice),
-            "broadcast" => Ok(Command::Broadcast),
+            "echo" => Ok(Command::Echo),
+            "transcribe" => Ok(Command::Transcribe),
+            "model" => Ok(Command::Model),
+            "temperature" => Ok(Command::Temperature),
+            "persona" => Ok(Command::Persona),
+            "voicecfg" => Ok(Command::VoiceCfg),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Command {
+    fn required_role(&self) -> UserRole {
+        match self {
+            Command::Help => UserRole::Readonly,
+            _ => UserRole::User,
+        }
+    }
+}
+
+impl FromStr for AdminCommand {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "broadcast" => Ok(AdminCommand::Broadcast),
+            "adduser" => Ok(AdminCommand::AddUser),
+            "removeuser" => Ok(AdminCommand::RemoveUser),
+            "listusers" => Ok(AdminCommand::ListUsers),
+            "setuser" => Ok(AdminCommand::SetUser),
+            "invite" => Ok(AdminCommand::Invite),
+            "addgroup" => Ok(AdminCommand::AddGroup),
+            "removegroup" => Ok(AdminCommand::RemoveGroup),
+            "listgroups" => Ok(AdminCommand::ListGroups),
+            "groupmode" => Ok(AdminCommand::GroupMode),
             _ => Err(()),
         }
     }
@@ -42,71 +139,1015 @@ pub async fn on_receive_command(
     bot: Bot,
     msg: Message,
     state: Arc<Mutex<State>>,
+    db: DB,
 ) {
-    let user_request = find_user_by_username(&state_users, msg.chat.username().unwrap());
     let message = msg.text().unwrap();
     let (_, command_line) = message.split_at(1);
 
     let substrings: Vec<&str> = command_line.split_whitespace().collect();
-    let command = substrings[0];
+    let command = match substrings.first() {
+        Some(command) => *command,
+        None => return,
+    };
+
+    // In groups commands may be addressed to a specific bot as `/command@botname`.
+    let command = match command.split_once('@') {
+        Some((command, bot_username)) => {
+            if !bot_username.eq_ignore_ascii_case(&state.lock().unwrap().bot_username) {
+                return;
+            }
+            command
+        }
+        None => command,
+    };
+
+    let user_request = if msg.chat.is_private() {
+        authorize_user(&state_users, &msg, &db, &state).await
+    } else {
+        authorize_group_member(&state_users, &msg, &db, &state).await
+    };
+
+    if let Some(user) = &user_request {
+        let result = if let Ok(cmd) = Command::from_str(command) {
+            if user.role < cmd.required_role() {
+                reply(bot, &msg, PERMISSION_DENIED).await;
+                return;
+            }
+
+            execute_command(cmd, user, &substrings, bot.clone(), &msg, &state, &db).await
+        } else if let Ok(cmd) = AdminCommand::from_str(command) {
+            if user.role < UserRole::Admin {
+                reply(bot, &msg, PERMISSION_DENIED).await;
+                return;
+            }
+
+            execute_admin_command(cmd, user, &substrings, bot.clone(), &msg, &state, &db).await
+        } else {
+            return;
+        };
+
+        if let Err(err) = result {
+            report_db_error(bot, msg.chat.id, topic_id(&msg), &err).await;
+        }
+    } else if let ("start", Some(code), true) = (command, substrings.get(1), msg.chat.is_private())
+    {
+        if let Err(err) = join_with_invite(code, bot.clone(), &msg, &state, &db).await {
+            report_db_error(bot, msg.chat.id, topic_id(&msg), &err).await;
+        }
+    }
+}
+
+/// Answers into the chat, and the forum topic, the command came from.
+async fn reply(bot: Bot, msg: &Message, message: &str) {
+    send_thread_message(bot, msg.chat.id, topic_id(msg), message).await;
+}
+
+/// Handles the `/start <code>` deep link of an unknown user.
+async fn join_with_invite(
+    code: &str,
+    bot: Bot,
+    msg: &Message,
+    state: &Arc<Mutex<State>>,
+    db: &DB,
+) -> Result<(), DbError> {
+    let from = match msg.from() {
+        Some(from) => from,
+        None => return Ok(()),
+    };
+
+    // Users without a public username are listed by their Telegram id.
+    let user_name = from
+        .username
+        .clone()
+        .unwrap_or_else(|| from.id.0.to_string());
+    let contact_form = default_contact_form();
+
+    let redeemed = db
+        .redeem_invite(
+            code,
+            from.id.0 as i64,
+            &user_name,
+            &from.first_name,
+            &contact_form,
+        )
+        .await?;
+
+    let issuer_chat_id = match redeemed {
+        InviteRedemption::Joined { issuer_chat_id } => issuer_chat_id,
+        InviteRedemption::AlreadyMember => {
+            refresh_users(db, state).await?;
+            reply(
+                bot,
+                msg,
+                "You already have access. Send /help to see what I can do",
+            )
+            .await;
+            return Ok(());
+        }
+        InviteRedemption::Invalid => {
+            reply(bot, msg, "This invite is invalid or expired").await;
+            return Ok(());
+        }
+    };
+
+    refresh_users(db, state).await?;
+    log::info!("User @{} joined with an invite", user_name);
+
+    if let Some(chat_id) = issuer_chat_id {
+        let message = format!(
+            "User @{} ({}) joined with your invite",
+            user_name, from.first_name
+        );
+        send_message(bot.clone(), chat_id, &message).await;
+    }
+
+    reply(bot, msg, "Welcome! Send /help to see what I can do").await;
+    Ok(())
+}
+
+async fn execute_command(
+    cmd: Command,
+    user: &User,
+    substrings: &[&str],
+    bot: Bot,
+    msg: &Message,
+    state: &Arc<Mutex<State>>,
+    db: &DB,
+) -> Result<(), DbError> {
+    match cmd {
+        Command::Help => {
+            let mut help = Command::descriptions().to_string();
+            if user.role >= UserRole::Admin {
+                help = format!("{}\n\n{}", help, AdminCommand::descriptions());
+            }
+
+            reply(bot, msg, &help).await;
+        }
+
+        Command::New => {
+            let group = find_group(state, msg.chat.id);
+            db.drop_history(conversation_for(msg, group.as_ref()))
+                .await?;
+            reply(bot, msg, "New conversation started").await;
+        }
 
-    let db = DB::new();
+        Command::Text => {
+            db.disable_voice(user.id).await?;
+            refresh_users(db, state).await?;
 
-    if let Some(user) = user_request {
-        match Command::from_str(command) {
-            Ok(cmd) => match cmd {
-                Command::Help => {
-                    send_message(bot, msg.chat.id, &Command::descriptions().to_string()).await;
+            reply(bot, msg, "Text responses enabled").await;
+        }
+
+        Command::Voice => {
+            db.enable_voice(user.id).await?;
+            refresh_users(db, state).await?;
+
+            reply(bot, msg, "Voice responses enabled").await;
+        }
+
+        Command::Echo => {
+            let is_echo = !user.is_echo;
+            db.set_echo(user.id, is_echo).await?;
+            refresh_users(db, state).await?;
+
+            let message = if is_echo {
+                "Voice message echo enabled"
+            } else {
+                "Voice message echo disabled"
+            };
+            reply(bot, msg, message).await;
+        }
+
+        Command::Transcribe => {
+            let is_transcribe_only = !user.is_transcribe_only;
+            db.set_transcribe_only(user.id, is_transcribe_only).await?;
+            refresh_users(db, state).await?;
+
+            let message = if is_transcribe_only {
+                "Transcription mode enabled, voice messages will only be transcribed"
+            } else {
+                "Transcription mode disabled"
+            };
+            reply(bot, msg, message).await;
+        }
+
+        Command::Model => {
+            let current_model = user
+                .model
+                .as_deref()
+                .and_then(gpt::find_model)
+                .unwrap_or_else(gpt::default_model);
+
+            let message = match substrings.get(1).copied() {
+                None => {
+                    let models: Vec<String> = gpt::ALLOWED_MODELS
+                        .iter()
+                        .map(|model| {
+                            let marker = if *model == current_model {
+                                " (current)"
+                            } else {
+                                ""
+                            };
+                            format!("- {}{}", model, marker)
+                        })
+                        .collect();
+                    format!("Available models:\n{}", models.join("\n"))
+                }
+                Some("default") => {
+                    db.set_model(user.id, None).await?;
+                    refresh_users(db, state).await?;
+                    format!("Model reset to default: {}", gpt::default_model())
                 }
+                Some(name) => match gpt::find_model(name) {
+                    Some(model) => {
+                        db.set_model(user.id, Some(model)).await?;
+                        refresh_users(db, state).await?;
+                        format!("Model changed to {}", model)
+                    }
+                    None => format!(
+                        "Unknown model {}, available: {}",
+                        name,
+                        gpt::ALLOWED_MODELS.join(", ")
+                    ),
+                },
+            };
+
+            reply(bot, msg, &message).await;
+        }
 
-                Command::New => {
-                    db.drop_history(msg.chat.id);
-                    send_message(bot, msg.chat.id, "New conversation started").await;
+        Command::Temperature => {
+            let message = match substrings.get(1).copied() {
+                None => format!(
+                    "Current temperature: {}",
+                    user.temperature.unwrap_or_else(gpt::default_temperature)
+                ),
+                Some("default") => {
+                    db.set_temperature(user.id, None).await?;
+                    refresh_users(db, state).await?;
+                    format!(
+                        "Temperature reset to default: {}",
+                        gpt::default_temperature()
+                    )
                 }
+                Some(value) => match value.parse::<f32>() {
+                    Ok(temperature) if gpt::is_valid_temperature(temperature) => {
+                        db.set_temperature(user.id, Some(temperature)).await?;
+                        refresh_users(db, state).await?;
+                        format!("Temperature changed to {}", temperature)
+                    }
+                    _ => format!(
+                        "Temperature must be a number between {} and {}",
+                        gpt::MIN_TEMPERATURE,
+                        gpt::MAX_TEMPERATURE
+                    ),
+                },
+            };
+
+            reply(bot, msg, &message).await;
+        }
 
-                Command::Text => {
-                    db.disable_voice(&user.user_name);
-                    let users_list = db.get_users().unwrap();
-                    state.lock().unwrap().users = Mutex::new(users_list);
+        Command::Persona => {
+            let current_persona = gpt::user_persona_name(user);
 
-                    send_message(bot, msg.chat.id, "Text responses enabled").await;
+            let message = match substrings.get(1).copied() {
+                None => {
+                    let personas: Vec<String> = db
+                        .get_personas()
+                        .await?
+                        .iter()
+                        .map(|persona| {
+                            let marker = if persona.name == current_persona {
+                                " (current)"
+                            } else {
+                                ""
+                            };
+                            let language = persona.language.as_deref().unwrap_or("any");
+                            format!("- {} [{}]{}", persona.name, language, marker)
+                        })
+                        .collect();
+                    format!("Available personas:\n{}", personas.join("\n"))
                 }
+                Some(name) => match db.get_persona(name).await? {
+                    Some(persona) => {
+                        db.set_persona(user.id, Some(&persona.name)).await?;
+                        refresh_users(db, state).await?;
+                        format!(
+                            "Persona changed to {}, use /new to start a fresh conversation",
+                            persona.name
+                        )
+                    }
+                    None => format!("Unknown persona {}, see /persona for the list", name),
+                },
+            };
+
+            reply(bot, msg, &message).await;
+        }
 
-                Command::Voice => {
-                    db.enable_voice(&user.user_name);
-                    let users_list = db.get_users().unwrap();
-                    state.lock().unwrap().users = Mutex::new(users_list);
+        Command::VoiceCfg => {
+            let voices = fetch_voices().await;
+            let mut request = bot
+                .send_message(msg.chat.id, voice_settings_text(user, &voices))
+                .reply_markup(voice_keyboard(user, &voices));
+            if let Some(thread_id) = topic_id(msg) {
+                request = request.message_thread_id(thread_id);
+            }
+
+            if let Err(err) = request.await {
+                log::error!("Failed to send voice settings: {}", err);
+                sentry::capture_error(&err);
+            }
+        }
+    }
 
-                    send_message(bot, msg.chat.id, "Voice responses enabled").await;
+    Ok(())
+}
+
+/// Handles the /voicecfg keyboard, buttons change the settings of whoever presses them.
+/// In groups the keyboard message is shared, so it is not rewritten with the
+/// settings of every member who presses a button.
+pub async fn on_receive_callback_query(
+    state_users: Vec<User>,
+    bot: Bot,
+    query: CallbackQuery,
+    state: Arc<Mutex<State>>,
+    db: DB,
+) {
+    let action = match query
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix(VOICECFG_PREFIX))
+    {
+        Some(action) => action,
+        None => return,
+    };
+
+    let answer = match find_user(&state_users, &query.from) {
+        Some(user) if user.role >= UserRole::User => {
+            let result = apply_voice_action(
+                action,
+                user,
+                query.message.as_ref(),
+                bot.clone(),
+                &state,
+                &db,
+            )
+            .await;
+
+            match result {
+                Ok(answer) => answer,
+                Err(err) => {
+                    log::error!("Failed to change voice settings: {}", err);
+                    sentry::capture_error(&err);
+                    "Failed to change voice settings".to_string()
                 }
+            }
+        }
+        Some(_) => PERMISSION_DENIED.to_string(),
+        None => "Access denied".to_string(),
+    };
+
+    if let Err(err) = bot.answer_callback_query(&query.id).text(answer).await {
+        sentry::capture_error(&err);
+    }
+}
 
-                Command::Broadcast => {
-                    let text: String = substrings[1..].join(" ");
+/// Applies a `voice:<id>`, `rate:<value>` or `reset` button and, in private chats,
+/// shows the new settings in place of the old ones. Returns the notification for the user.
+async fn apply_voice_action(
+    action: &str,
+    user: &User,
+    message: Option<&Message>,
+    bot: Bot,
+    state: &Arc<Mutex<State>>,
+    db: &DB,
+) -> Result<String, DbError> {
+    let voices = fetch_voices().await;
+
+    let answer = match action.split_once(':') {
+        Some(("voice", id)) => match voices.iter().find(|voice| voice.id == id) {
+            Some(voice) => {
+                db.set_tts_voice(user.id, Some(&voice.id), voice.language.as_deref())
+                    .await?;
+                format!("Voice changed to {}", voice.title())
+            }
+            None => return Ok("This voice is no longer available".to_string()),
+        },
+        Some(("rate", value)) => match value.parse::<f32>() {
+            Ok(rate) if tts::is_valid_rate(rate) => {
+                db.set_tts_rate(user.id, Some(rate)).await?;
+                format!("Speech rate changed to {}x", rate)
+            }
+            _ => return Ok("Unknown speech rate".to_string()),
+        },
+        None if action == "reset" => {
+            db.set_tts_voice(user.id, None, None).await?;
+            db.set_tts_rate(user.id, None).await?;
+            "Voice settings reset to defaults".to_string()
+        }
+        _ => return Ok(String::new()),
+    };
+
+    refresh_users(db, state).await?;
+
+    let message = message.filter(|message| message.chat.is_private());
+    let updated_user = state
+        .lock()
+        .unwrap()
+        .users
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|updated_user| updated_user.id == user.id)
+        .cloned();
+
+    if let (Some(message), Some(user)) = (message, updated_user) {
+        let result = bot
+            .edit_message_text(
+                message.chat.id,
+                message.id,
+                voice_settings_text(&user, &voices),
+            )
+            .reply_markup(voice_keyboard(&user, &voices))
+            .await;
 
-                    if text.trim().is_empty() {
-                        return;
+        if let Err(err) = result {
+            log::warn!("Failed to update voice settings message: {}", err);
+        }
+    }
+
+    Ok(answer)
+}
+
+/// Voices advertised by the TTS server, an unavailable list only hides the voice buttons.
+async fn fetch_voices() -> Vec<Voice> {
+    match tts::voices().await {
+        Ok(voices) => voices,
+        Err(err) => {
+            log::error!("Failed to load TTS voices: {}", err);
+            sentry::capture_error(&*err);
+            Vec::new()
+        }
+    }
+}
+
+fn voice_settings_text(user: &User, voices: &[Voice]) -> String {
+    let voice = match user.tts_voice.as_deref() {
+        Some(id) => voices
+            .iter()
+            .find(|voice| voice.id == id)
+            .map_or(id, |voice| voice.title()),
+        None => "default",
+    };
+    let rate = user
+        .tts_rate
+        .map_or("default".to_string(), |rate| format!("{}x", rate));
+    let language = user.tts_language.as_deref().unwrap_or("auto");
+
+    let mut text = format!(
+        "Voice: {}\nSpeech rate: {}\nLanguage: {}",
+        voice, rate, language
+    );
+    if voices.is_empty() {
+        text.push_str(
+            "\n\nThe TTS server doesn't list its voices, only the speech rate can be changed",
+        );
+    }
+
+    text
+}
+
+fn voice_keyboard(user: &User, voices: &[Voice]) -> InlineKeyboardMarkup {
+    let mark = |title: String, is_current: bool| {
+        if is_current {
+            format!("✓ {}", title)
+        } else {
+            title
+        }
+    };
+
+    let voice_buttons: Vec<InlineKeyboardButton> = voices
+        .iter()
+        .map(|voice| (voice, format!("{}voice:{}", VOICECFG_PREFIX, voice.id)))
+        .filter(|(_, data)| data.len() <= CALLBACK_DATA_LIMIT)
+        .take(MAX_VOICE_BUTTONS)
+        .map(|(voice, data)| {
+            let title = match voice.language.as_deref() {
+                Some(language) => format!("{} [{}]", voice.title(), language),
+                None => voice.title().to_string(),
+            };
+            let is_current = user.tts_voice.as_deref() == Some(voice.id.as_str());
+            InlineKeyboardButton::callback(mark(title, is_current), data)
+        })
+        .collect();
+
+    let mut rows: Vec<Vec<InlineKeyboardButton>> =
+        voice_buttons.chunks(3).map(|row| row.to_vec()).collect();
+
+    let current_rate = user.tts_rate.unwrap_or(1.0);
+    rows.push(
+        RATE_OPTIONS
+            .iter()
+            .map(|rate| {
+                let is_current = (current_rate - rate).abs() < 0.01;
+                InlineKeyboardButton::callback(
+                    mark(format!("{}x", rate), is_current),
+                    format!("{}rate:{}", VOICECFG_PREFIX, rate),
+                )
+            })
+            .collect(),
+    );
+    rows.push(vec![InlineKeyboardButton::callback(
+        "Reset to defaults",
+        format!("{}reset", VOICECFG_PREFIX),
+    )]);
+
+    InlineKeyboardMarkup::new(rows)
+}
+
+async fn execute_admin_command(
+    cmd: AdminCommand,
+    admin: &User,
+    substrings: &[&str],
+    bot: Bot,
+    msg: &Message,
+    state: &Arc<Mutex<State>>,
+    db: &DB,
+) -> Result<(), DbError> {
+    match cmd {
+        AdminCommand::Broadcast => {
+            let text: String = substrings[1..].join(" ");
+
+            if text.trim().is_empty() {
+                return Ok(());
+            }
+
+            let users_list = db.get_users().await?;
+            let mut users_count = 0;
+            for user in users_list.iter() {
+                if let Some(chat_id) = user.chat_id {
+                    send_message(bot.clone(), chat_id, &text).await;
+                    users_count += 1;
+                }
+            }
+            let message = format!(
+                "Message successfully broadcasted for {} users!",
+                users_count
+            );
+
+            reply(bot, msg, &message).await;
+        }
+
+        AdminCommand::AddUser => {
+            // The contact name may contain spaces, the contact form is always the last word.
+            let message = if substrings.len() < 4 {
+                "Usage: /adduser <username> <contact_name> <contact_form>".to_string()
+            } else {
+                // Users without a public username can be added by their numeric Telegram id.
+                let user_name = normalize_username(substrings[1]);
+                let telegram_id = user_name.parse::<i64>().ok();
+                let contact_name = substrings[2..substrings.len() - 1].join(" ");
+                let contact_form = substrings[substrings.len() - 1];
+
+                if db
+                    .add_user(&user_name, telegram_id, &contact_name, contact_form)
+                    .await?
+                {
+                    refresh_users(db, state).await?;
+                    format!("User @{} added", user_name)
+                } else {
+                    format!("User @{} already exists", user_name)
+                }
+            };
+
+            reply(bot, msg, &message).await;
+        }
+
+        AdminCommand::RemoveUser => {
+            let users = db.get_users().await?;
+            let message = match substrings.get(1) {
+                None => "Usage: /removeuser <username|telegram_id>".to_string(),
+                Some(target) => match find_target_user(&users, target) {
+                    Ok(user) if user.id == admin.id => "You can't remove yourself".to_string(),
+                    Ok(user) if is_last_admin(&users, &user) => LAST_ADMIN.to_string(),
+                    Ok(user) => {
+                        if db.remove_user(user.id).await? {
+                            refresh_users(db, state).await?;
+                            format!("User @{} removed", user.user_name)
+                        } else {
+                            format!("User @{} not found", user.user_name)
+                        }
                     }
+                    Err(error) => error,
+                },
+            };
+
+            reply(bot, msg, &message).await;
+        }
+
+        AdminCommand::ListUsers => {
+            let users: Vec<String> = db
+                .get_users()
+                .await?
+                .iter()
+                .map(|user| {
+                    let telegram_id = user
+                        .telegram_id
+                        .map_or("none".to_string(), |telegram_id| telegram_id.to_string());
+                    format!(
+                        "- @{} [id: {}] ({}, {}) role: {}, voice: {}, chat: {}",
+                        user.user_name,
+                        telegram_id,
+                        user.contact_name,
+                        user.contact_form,
+                        user.role.as_str(),
+                        if user.is_voice { "on" } else { "off" },
+                        if user.chat_id.is_some() { "yes" } else { "no" }
+                    )
+                })
+                .collect();
 
-                    let users_list = db.get_users().unwrap();
-                    let mut users_count = 0;
-                    for (_, user) in users_list.iter().enumerate() {
-                        match user.chat_id {
-                            Some(chat_id) => {
-                                send_message(bot.clone(), chat_id, &text).await;
-                                users_count += 1;
-                            }
-                            None => {}
+            let message = if users.is_empty() {
+                "No users".to_string()
+            } else {
+                format!("Users:\n{}", users.join("\n"))
+            };
+
+            reply(bot, msg, &message).await;
+        }
+
+        AdminCommand::SetUser => {
+            let message = if substrings.len() < 4 {
+                format!(
+                    "Usage: /setuser <username|telegram_id> <field> <value>\nFields: {}",
+                    USER_FIELDS.join(", ")
+                )
+            } else {
+                let value = substrings[3..].join(" ");
+                let users = db.get_users().await?;
+                let target = find_target_user(&users, substrings[1]);
+
+                match (target, parse_user_field(substrings[2], &value, db).await?) {
+                    (Ok(user), Ok(("role", value)))
+                        if value != Value::Text(UserRole::Admin.as_str().to_string())
+                            && is_last_admin(&users, &user) =>
+                    {
+                        LAST_ADMIN.to_string()
+                    }
+                    (Ok(user), Ok((column, value))) => {
+                        if db.set_user_field(user.id, column, value).await? {
+                            refresh_users(db, state).await?;
+                            format!("User @{} updated: {}", user.user_name, column)
+                        } else {
+                            format!("User @{} not found", user.user_name)
                         }
                     }
-                    let message = format!(
-                        "Message successfully broadcasted for {} users!",
-                        users_count
-                    );
+                    (Err(error), _) | (_, Err(error)) => error,
+                }
+            };
+
+            reply(bot, msg, &message).await;
+        }
+
+        AdminCommand::Invite => {
+            let uses = substrings.get(1).map_or(Some(DEFAULT_INVITE_USES), |uses| {
+                uses.parse::<u32>().ok().filter(|uses| *uses > 0)
+            });
+            let ttl_text = substrings.get(2).copied().unwrap_or(DEFAULT_INVITE_TTL);
+            let ttl = parse_ttl(ttl_text);
+
+            let message = match (uses, ttl) {
+                (Some(uses), Some(ttl)) => {
+                    let code = uuid::Uuid::new_v4().simple().to_string();
+                    db.create_invite(&code, admin.id, uses, ttl).await?;
+                    let bot_username = state.lock().unwrap().bot_username.clone();
+                    invite_message(&bot_username, &code, uses, ttl_text)
+                }
+                _ => format!(
+                    "Usage: /invite [uses] [ttl]\nDefaults: {} use, valid for {}, at most 365d",
+                    DEFAULT_INVITE_USES, DEFAULT_INVITE_TTL
+                ),
+            };
+
+            reply(bot, msg, &message).await;
+        }
+
+        AdminCommand::AddGroup => {
+            let message = if msg.chat.is_private() {
+                "Send /addgroup in the group you want to authorize".to_string()
+            } else if db.add_group(msg.chat.id, msg.chat.title()).await? {
+                refresh_groups(db, state).await?;
+                "Group authorized, mention me or reply to my messages to talk".to_string()
+            } else {
+                "Group is already authorized".to_string()
+            };
+
+            reply(bot, msg, &message).await;
+        }
+
+        AdminCommand::RemoveGroup => {
+            let chat_id = match substrings.get(1) {
+                Some(chat_id) => chat_id.parse::<i64>().ok().map(ChatId),
+                None => Some(msg.chat.id),
+            };
+
+            let message = match chat_id {
+                None => "Usage: /removegroup [chat_id]".to_string(),
+                Some(chat_id) => {
+                    if db.remove_group(chat_id).await? {
+                        refresh_groups(db, state).await?;
+                        format!("Group {} removed", chat_id)
+                    } else {
+                        format!("Group {} not found", chat_id)
+                    }
+                }
+            };
+
+            reply(bot, msg, &message).await;
+        }
+
+        AdminCommand::ListGroups => {
+            let groups: Vec<String> = db
+                .get_groups()
+                .await?
+                .iter()
+                .map(|group| {
+                    format!(
+                        "- {} ({}) history: {}",
+                        group.chat_id,
+                        group.title.as_deref().unwrap_or("untitled"),
+                        if group.shared_history {
+                            "shared"
+                        } else {
+                            "member"
+                        }
+                    )
+                })
+                .collect();
+
+            let message = if groups.is_empty() {
+                "No groups".to_string()
+            } else {
+                format!("Groups:\n{}", groups.join("\n"))
+            };
 
-                    send_message(bot, msg.chat.id, &message).await;
+            reply(bot, msg, &message).await;
+        }
+
+        AdminCommand::GroupMode => {
+            let shared_history = match substrings.get(1) {
+                Some(&"shared") => Some(true),
+                Some(&"member") => Some(false),
+                _ => None,
+            };
+
+            let message = match shared_history {
+                None => "Usage: /groupmode <shared|member>".to_string(),
+                Some(_) if msg.chat.is_private() => {
+                    "Send /groupmode in the group you want to configure".to_string()
+                }
+                Some(shared_history) => {
+                    if db
+                        .set_group_shared_history(msg.chat.id, shared_history)
+                        .await?
+                    {
+                        refresh_groups(db, state).await?;
+                        if shared_history {
+                            "The group now shares one conversation history".to_string()
+                        } else {
+                            "Every group member now has their own conversation history".to_string()
+                        }
+                    } else {
+                        "Group is not authorized, use /addgroup first".to_string()
+                    }
                 }
-            },
-            Err(_) => {}
+            };
+
+            reply(bot, msg, &message).await;
         }
     }
+
+    Ok(())
+}
+
+fn invite_message(bot_username: &str, code: &str, uses: u32, ttl: &str) -> String {
+    format!(
+        "Invite for {} user(s), valid for {}:\nhttps://t.me/{}?start={}",
+        uses, ttl, bot_username, code
+    )
+}
+
+/// Parses durations like `30m`, `12h` or `7d` into seconds, a bare number means hours.
+fn parse_ttl(ttl: &str) -> Option<i64> {
+    let (value, unit) = match ttl.char_indices().last()? {
+        (index, unit) if unit.is_ascii_alphabetic() => (&ttl[..index], unit),
+        _ => (ttl, 'h'),
+    };
+
+    let multiplier = match unit {
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+
+    value
+        .parse::<i64>()
+        .ok()
+        .filter(|value| *value > 0)
+        .and_then(|value| value.checked_mul(multiplier))
+        .filter(|seconds| *seconds <= MAX_INVITE_TTL_SECONDS)
+}
+
+const USER_FIELDS: &[&str] = &[
+    "contact_name",
+    "contact_form",
+    "language",
+    "model",
+    "temperature",
+    "persona",
+    "role",
+    "is_voice",
+    "tts_voice",
+    "tts_rate",
+    "tts_language",
+];
+
+/// Validates a `/setuser` field and value, `none` clears optional fields.
+/// The outer error is a database failure, the inner one a message for the admin.
+async fn parse_user_field(
+    field: &str,
+    value: &str,
+    db: &DB,
+) -> Result<Result<(&'static str, Value), String>, DbError> {
+    let is_none = value.eq_ignore_ascii_case("none");
+
+    let parsed = match field {
+        "contact_name" => Ok(("contact_name", Value::Text(value.to_string()))),
+        "contact_form" => Ok(("contact_form", Value::Text(value.to_string()))),
+        "language" if is_none => Ok(("language", Value::Null)),
+        "language" => Ok(("language", Value::Text(value.to_lowercase()))),
+        "model" if is_none => Ok(("model", Value::Null)),
+        "model" => match gpt::find_model(value) {
+            Some(model) => Ok(("model", Value::Text(model.to_string()))),
+            None => Err(format!(
+                "Unknown model {}, available: {}",
+                value,
+                gpt::ALLOWED_MODELS.join(", ")
+            )),
+        },
+        "temperature" if is_none => Ok(("temperature", Value::Null)),
+        "temperature" => match value.parse::<f32>() {
+            Ok(temperature) if gpt::is_valid_temperature(temperature) => {
+                Ok(("temperature", Value::Real(temperature as f64)))
+            }
+            _ => Err(format!(
+                "Temperature must be a number between {} and {}",
+                gpt::MIN_TEMPERATURE,
+                gpt::MAX_TEMPERATURE
+            )),
+        },
+        "persona" if is_none => Ok(("persona", Value::Null)),
+        "persona" => match db.get_persona(value).await? {
+            Some(persona) => Ok(("persona", Value::Text(persona.name))),
+            None => Err(format!("Unknown persona {}", value)),
+        },
+        "role" => match UserRole::from_str(value) {
+            Ok(role) => Ok(("role", Value::Text(role.as_str().to_string()))),
+            Err(_) => Err("Role must be one of: admin, user, readonly".to_string()),
+        },
+        "is_voice" => match value {
+            "1" | "on" | "true" => Ok(("is_voice", Value::Integer(1))),
+            "0" | "off" | "false" => Ok(("is_voice", Value::Integer(0))),
+            _ => Err("is_voice must be on or off".to_string()),
+        },
+        "tts_voice" if is_none => Ok(("tts_voice", Value::Null)),
+        "tts_voice" => Ok(("tts_voice", Value::Text(value.to_string()))),
+        "tts_rate" if is_none => Ok(("tts_rate", Value::Null)),
+        "tts_rate" => match value.parse::<f32>() {
+            Ok(rate) if tts::is_valid_rate(rate) => Ok(("tts_rate", Value::Real(rate as f64))),
+            _ => Err(format!(
+                "Speech rate must be a number between {} and {}",
+                tts::MIN_RATE,
+                tts::MAX_RATE
+            )),
+        },
+        "tts_language" if is_none => Ok(("tts_language", Value::Null)),
+        "tts_language" => Ok(("tts_language", Value::Text(value.to_lowercase()))),
+        _ => Err(format!(
+            "Unknown field {}, available: {}",
+            field,
+            USER_FIELDS.join(", ")
+        )),
+    };
+
+    Ok(parsed)
+}
+
+/// Whether the user is the only admin left, nobody could manage the bot without them.
+fn is_last_admin(users: &[User], user: &User) -> bool {
+    user.role == UserRole::Admin
+        && users
+            .iter()
+            .filter(|other| other.role == UserRole::Admin)
+            .count()
+            <= 1
+}
+
+/// Finds the user an admin command is about by Telegram id or username. Usernames
+/// are display only and may be shared, then the admin is asked for the id instead.
+fn find_target_user(users: &[User], target: &str) -> Result<User, String> {
+    let user_name = normalize_username(target);
+    let found: Vec<&User> = match user_name.parse::<i64>() {
+        Ok(telegram_id) => users
+            .iter()
+            .filter(|user| user.telegram_id == Some(telegram_id))
+            .collect(),
+        Err(_) => users
+            .iter()
+            .filter(|user| user.user_name.eq_ignore_ascii_case(&user_name))
+            .collect(),
+    };
+
+    match found.as_slice() {
+        [user] => Ok((*user).clone()),
+        [] => Err(format!("User {} not found", target)),
+        users => {
+            let ids: Vec<String> = users
+                .iter()
+                .filter_map(|user| user.telegram_id)
+                .map(|telegram_id| telegram_id.to_string())
+                .collect();
+            Err(format!(
+                "Several users are called @{}, use their Telegram id: {}",
+                user_name,
+                ids.join(", ")
+            ))
+        }
+    }
+}
+
+fn normalize_username(user_name: &str) -> String {
+    user_name.trim_start_matches('@').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ttl_units() {
+        assert_eq!(parse_ttl("30m"), Some(30 * 60));
+        assert_eq!(parse_ttl("12h"), Some(12 * 60 * 60));
+        assert_eq!(parse_ttl("7d"), Some(7 * 24 * 60 * 60));
+        assert_eq!(parse_ttl("5"), Some(5 * 60 * 60));
+    }
+
+    #[test]
+    fn rejects_invalid_ttl() {
+        assert_eq!(parse_ttl(""), None);
+        assert_eq!(parse_ttl("0h"), None);
+        assert_eq!(parse_ttl("-1d"), None);
+        assert_eq!(parse_ttl("3w"), None);
+        assert_eq!(parse_ttl("h"), None);
+    }
+
+    fn user(id: i64, role: UserRole) -> User {
+        User {
+            id,
+            telegram_id: Some(id),
+            user_name: format!("user{}", id),
+            chat_id: None,
+            contact_name: format!("User {}", id),
+            contact_form: "ты".to_string(),
+            is_voice: false,
+            language: None,
+            is_echo: false,
+            is_transcribe_only: false,
+            model: None,
+            temperature: None,
+            persona: None,
+            role,
+            tts_voice: None,
+            tts_rate: None,
+            tts_language: None,
+        }
+    }
+
+    #[test]
+    fn finds_last_admin() {
+        let users = vec![user(1, UserRole::Admin), user(2, UserRole::User)];
+        assert!(is_last_admin(&users, &users[0]));
+        assert!(!is_last_admin(&users, &users[1]));
+
+        let users = vec![user(1, UserRole::Admin), user(2, UserRole::Admin)];
+        assert!(!is_last_admin(&users, &users[0]));
+    }
+
+    #[test]
+    fn caps_ttl() {
+        assert_eq!(parse_ttl("365d"), Some(MAX_INVITE_TTL_SECONDS));
+        assert_eq!(parse_ttl("366d"), None);
+        assert_eq!(parse_ttl("999999999999999d"), None);
+        assert_eq!(parse_ttl("9223372036854775807m"), None);
+    }
 }
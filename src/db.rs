@@ -1,209 +1,879 @@
-use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::str::FromStr;
 
+use crate::migrations;
 use chatgpt::types::{ChatMessage, Role};
-use rusqlite::{Connection, Result};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::types::Value;
+use rusqlite::{named_params, Connection, OptionalExtension, Result, Row};
 use teloxide::prelude::ChatId;
 
+const DEFAULT_DATABASE_PATH: &str = "database.db";
+
+/// Upper bound of rows loaded for a conversation, the token budget trims it further.
+pub const HISTORY_MAX_MESSAGES: i64 = 200;
+
+#[derive(Clone)]
 pub struct DB {
-    connection: Arc<Mutex<Connection>>,
+    pool: Pool<SqliteConnectionManager>,
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    Sqlite(rusqlite::Error),
+    Pool(r2d2::Error),
+    Task(tokio::task::JoinError),
+    InvalidRole(String),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Sqlite(err) => write!(f, "SQLite error: {}", err),
+            DbError::Pool(err) => write!(f, "Database pool error: {}", err),
+            DbError::Task(err) => write!(f, "Database task failed: {}", err),
+            DbError::InvalidRole(role) => write!(f, "Invalid role in chat history: {}", role),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::Sqlite(err) => Some(err),
+            DbError::Pool(err) => Some(err),
+            DbError::Task(err) => Some(err),
+            DbError::InvalidRole(_) => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(err: rusqlite::Error) -> Self {
+        DbError::Sqlite(err)
+    }
+}
+
+impl From<r2d2::Error> for DbError {
+    fn from(err: r2d2::Error) -> Self {
+        DbError::Pool(err)
+    }
+}
+
+impl From<tokio::task::JoinError> for DbError {
+    fn from(err: tokio::task::JoinError) -> Self {
+        DbError::Task(err)
+    }
 }
 
 struct Message {
-    chat_id: String,
+    chat_id: i64,
+    thread_id: i32,
+    member_id: i64,
     message: String,
     role: String,
 }
 
 struct LoadedMessage {
+    id: i64,
     content: String,
-    role: Role,
+    role: String,
+}
+
+/// Key of a conversation history: a private chat, a whole group or a single member of a group,
+/// optionally within a forum topic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Conversation {
+    pub chat_id: ChatId,
+    /// Forum topic the conversation belongs to, replies are sent into it as well.
+    pub thread_id: Option<i32>,
+    /// Telegram id of the group member with their own history, `0` when the chat history is shared.
+    pub member_id: i64,
+}
+
+impl Conversation {
+    pub fn chat(chat_id: ChatId) -> Self {
+        Conversation {
+            chat_id,
+            thread_id: None,
+            member_id: 0,
+        }
+    }
+
+    pub fn member(chat_id: ChatId, member_id: i64) -> Self {
+        Conversation {
+            chat_id,
+            thread_id: None,
+            member_id,
+        }
+    }
+
+    pub fn in_thread(self, thread_id: Option<i32>) -> Self {
+        Conversation { thread_id, ..self }
+    }
+
+    /// Values of the `chat_id`, `thread_id` and `member_id` columns, `0` stands for no topic.
+    fn key(&self) -> (i64, i32, i64) {
+        (self.chat_id.0, self.thread_id.unwrap_or(0), self.member_id)
+    }
+}
+
+impl fmt::Display for Conversation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.chat_id)?;
+        if let Some(thread_id) = self.thread_id {
+            write!(f, "#{}", thread_id)?;
+        }
+        if self.member_id != 0 {
+            write!(f, "/{}", self.member_id)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HistoryMessage {
+    pub id: i64,
+    pub message: ChatMessage,
+}
+
+#[derive(Clone, Debug)]
+pub struct Summary {
+    pub content: String,
+    pub last_message_id: i64,
 }
 
 #[derive(Clone, Debug)]
 pub struct User {
+    pub id: i64,
+    /// Stable Telegram user id used for authorization, `None` until the user writes to the bot.
+    pub telegram_id: Option<i64>,
+    /// Display only, usernames can be changed or taken over by someone else.
     pub user_name: String,
     pub chat_id: Option<ChatId>,
     pub contact_name: String,
     pub contact_form: String,
     pub is_voice: bool,
+    pub language: Option<String>,
+    pub is_echo: bool,
+    pub is_transcribe_only: bool,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub persona: Option<String>,
+    pub role: UserRole,
+    /// TTS speaker chosen with /voicecfg, overrides the persona voice.
+    pub tts_voice: Option<String>,
+    pub tts_rate: Option<f32>,
+    /// Language of the chosen TTS voice.
+    pub tts_language: Option<String>,
 }
 
-impl DB {
-    pub fn new() -> Self {
-        DB {
-            connection: Arc::new(Mutex::new(Connection::open("database.db").unwrap())),
+/// Access level of a user, ordered from the least to the most privileged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum UserRole {
+    Readonly,
+    User,
+    Admin,
+}
+
+impl UserRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserRole::Readonly => "readonly",
+            UserRole::User => "user",
+            UserRole::Admin => "admin",
         }
     }
+}
 
-    pub async fn history_migration(&self) {
-        let result = self.get_connection().execute(
-            "CREATE TABLE chat_history (
-                id          INTEGER PRIMARY KEY,
-                chat_id     INTEGER  NOT NULL,
-                message     TEXT NOT NULL,
-                role        VARCHAR(20) NOT NULL,
-                created_at  TEXT DEFAULT CURRENT_TIMESTAMP
-            )",
-            (),
-        );
-
-        match result {
-            Ok(_) => {
-                log::info!("Table [history] successfully created")
-            }
-            Err(err) => {
-                log::warn!("Warning in [history] creation: {}", err)
-            }
+impl FromStr for UserRole {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "readonly" => Ok(UserRole::Readonly),
+            "user" => Ok(UserRole::User),
+            "admin" => Ok(UserRole::Admin),
+            _ => Err(()),
         }
     }
+}
 
-    pub async fn users_migration(&self) {
-        let result = self.get_connection().execute(
-            "CREATE TABLE users (
-                id              INTEGER PRIMARY KEY,
-                username        VARCHAR(100) NOT NULL,
-                chat_id         INTEGER DEFAULT NULL,
-                contact_name    VARCHAR(100) NOT NULL,
-                contact_form    VARCHAR(20) NOT NULL,
-                is_voice        TINNYINT(1) DEFAULT 0
-            )",
-            (),
-        );
-
-        match result {
-            Ok(_) => {
-                log::info!("Table [users] successfully created")
-            }
-            Err(err) => {
-                log::warn!("Warning in [users] creation: {}", err)
-            }
-        }
+#[derive(Clone, Debug)]
+pub struct Persona {
+    pub name: String,
+    pub system_prompt: String,
+    pub greeting: Option<String>,
+    pub language: Option<String>,
+    pub voice: Option<String>,
+}
+
+/// Group chat the bot is allowed to talk in.
+#[derive(Clone, Debug)]
+pub struct Group {
+    pub chat_id: ChatId,
+    pub title: Option<String>,
+    /// One history for the whole group, otherwise every member has their own.
+    pub shared_history: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum InviteRedemption {
+    /// A new user was created.
+    Joined {
+        /// Chat of the admin who issued the invite, `None` if they never wrote to the bot or were removed.
+        issuer_chat_id: Option<ChatId>,
+    },
+    /// The user is already in the table, the invite is left unused.
+    AlreadyMember,
+    /// The code is unknown, expired or used up.
+    Invalid,
+}
+
+impl DB {
+    /// Opens the pool for the database at `DATABASE_PATH` (default: `database.db`).
+    pub fn from_env() -> Result<Self, DbError> {
+        let path =
+            std::env::var("DATABASE_PATH").unwrap_or_else(|_| DEFAULT_DATABASE_PATH.to_string());
+        DB::new(&path)
+    }
+
+    pub fn new(path: &str) -> Result<Self, DbError> {
+        let manager = SqliteConnectionManager::file(path).with_init(|connection| {
+            connection.execute_batch("PRAGMA journal_mode = WAL; PRAGMA busy_timeout = 5000;")
+        });
+
+        Ok(DB {
+            pool: Pool::new(manager)?,
+        })
     }
 
-    pub fn save_message(&self, chat_id: ChatId, role: Role, message: &str) {
+    pub async fn migrate(&self) -> Result<(), DbError> {
+        self.run(|connection| {
+            migrations::run(connection)?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn save_message(
+        &self,
+        conversation: Conversation,
+        role: Role,
+        message: &str,
+    ) -> Result<(), DbError> {
+        let (chat_id, thread_id, member_id) = conversation.key();
         let msg_data = Message {
-            chat_id: chat_id.to_string(),
+            chat_id,
+            thread_id,
+            member_id,
             message: message.to_string(),
             role: DB::role_to_string(role),
         };
 
-        self.get_connection()
-            .execute(
-                "INSERT INTO chat_history (chat_id, message, role) VALUES (?1, ?2, ?3)",
-                (&msg_data.chat_id, &msg_data.message, &msg_data.role),
-            )
-            .unwrap();
-    }
-
-    pub fn drop_history(&self, chat_id: ChatId) {
-        let connection = self.get_connection();
-        let mut request = connection
-            .prepare("DELETE FROM chat_history WHERE chat_id = :chat_id")
-            .unwrap();
-        request
-            .execute(&[(":chat_id", &chat_id.to_string())])
-            .unwrap();
-    }
-
-    pub fn get_history(&self, chat_id: ChatId) -> Result<Vec<ChatMessage>, rusqlite::Error> {
-        let connection = self.get_connection();
-        let mut stmt = connection.prepare(
-            "SELECT message, role FROM (SELECT message, role, created_at FROM chat_history WHERE chat_id = ? ORDER BY created_at DESC LIMIT 10) ORDER BY created_at ASC",
-        )?;
-
-        let message_iter = stmt
-            .query_map([chat_id.to_string()], |row| {
-                Ok(LoadedMessage {
-                    content: row.get(0)?,
-                    role: DB::string_to_role(row.get::<_, String>(1)?.as_str()),
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO chat_history (chat_id, thread_id, member_id, message, role) VALUES (?1, ?2, ?3, ?4, ?5)",
+                (
+                    msg_data.chat_id,
+                    msg_data.thread_id,
+                    msg_data.member_id,
+                    &msg_data.message,
+                    &msg_data.role,
+                ),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn drop_history(&self, conversation: Conversation) -> Result<(), DbError> {
+        let key = conversation.key();
+
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "DELETE FROM chat_history WHERE chat_id = ?1 AND thread_id = ?2 AND member_id = ?3",
+                key,
+            )?;
+            transaction.execute(
+                "DELETE FROM chat_summaries WHERE chat_id = ?1 AND thread_id = ?2 AND member_id = ?3",
+                key,
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns the newest messages stored after `after_id`, oldest first.
+    pub async fn get_history(
+        &self,
+        conversation: Conversation,
+        after_id: i64,
+    ) -> Result<Vec<HistoryMessage>, DbError> {
+        let (chat_id, thread_id, member_id) = conversation.key();
+
+        self.run(move |connection| {
+            let mut stmt = connection.prepare(
+                "SELECT id, message, role FROM (SELECT id, message, role FROM chat_history WHERE chat_id = ?1 AND thread_id = ?2 AND member_id = ?3 AND id > ?4 ORDER BY id DESC LIMIT ?5) ORDER BY id ASC",
+            )?;
+            let rows = stmt.query_map(
+                (chat_id, thread_id, member_id, after_id, HISTORY_MAX_MESSAGES),
+                DB::load_message,
+            )?;
+
+            DB::collect_history(rows)
+        })
+        .await
+    }
+
+    /// Returns at most `limit` messages with `after_id < id <= up_to_id`, oldest first.
+    pub async fn get_history_range(
+        &self,
+        conversation: Conversation,
+        after_id: i64,
+        up_to_id: i64,
+        limit: i64,
+    ) -> Result<Vec<HistoryMessage>, DbError> {
+        let (chat_id, thread_id, member_id) = conversation.key();
+
+        self.run(move |connection| {
+            let mut stmt = connection.prepare(
+                "SELECT id, message, role FROM chat_history WHERE chat_id = ?1 AND thread_id = ?2 AND member_id = ?3 AND id > ?4 AND id <= ?5 ORDER BY id ASC LIMIT ?6",
+            )?;
+            let rows = stmt.query_map(
+                (chat_id, thread_id, member_id, after_id, up_to_id, limit),
+                DB::load_message,
+            )?;
+
+            DB::collect_history(rows)
+        })
+        .await
+    }
+
+    pub async fn get_summary(
+        &self,
+        conversation: Conversation,
+    ) -> Result<Option<Summary>, DbError> {
+        let key = conversation.key();
+
+        self.run(move |connection| {
+            let summary = connection
+                .query_row(
+                    "SELECT summary, last_message_id FROM chat_summaries WHERE chat_id = ?1 AND thread_id = ?2 AND member_id = ?3",
+                    key,
+                    |row| {
+                        Ok(Summary {
+                            content: row.get(0)?,
+                            last_message_id: row.get(1)?,
+                        })
+                    },
+                )
+                .optional()?;
+            Ok(summary)
+        })
+        .await
+    }
+
+    /// Stores the summary unless a newer one (covering more messages) is already saved.
+    pub async fn save_summary(
+        &self,
+        conversation: Conversation,
+        summary: &str,
+        last_message_id: i64,
+    ) -> Result<(), DbError> {
+        let (chat_id, thread_id, member_id) = conversation.key();
+        let summary = summary.to_string();
+
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO chat_summaries (chat_id, thread_id, member_id, summary, last_message_id) VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT(chat_id, thread_id, member_id) DO UPDATE SET
+                    summary = excluded.summary,
+                    last_message_id = excluded.last_message_id,
+                    updated_at = CURRENT_TIMESTAMP
+                WHERE excluded.last_message_id > chat_summaries.last_message_id",
+                (chat_id, thread_id, member_id, &summary, last_message_id),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn enable_voice(&self, user_id: i64) -> Result<(), DbError> {
+        self.set_voice(user_id, true).await
+    }
+
+    pub async fn disable_voice(&self, user_id: i64) -> Result<(), DbError> {
+        self.set_voice(user_id, false).await
+    }
+
+    pub async fn set_echo(&self, user_id: i64, is_echo: bool) -> Result<(), DbError> {
+        self.run(move |connection| {
+            let mut request =
+                connection.prepare("UPDATE users SET is_echo = :is_echo WHERE id = :user_id")?;
+            request.execute(named_params! {":user_id": user_id, ":is_echo": is_echo})?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn set_transcribe_only(
+        &self,
+        user_id: i64,
+        is_transcribe_only: bool,
+    ) -> Result<(), DbError> {
+        self.run(move |connection| {
+            let mut request = connection.prepare(
+                "UPDATE users SET is_transcribe_only = :is_transcribe_only WHERE id = :user_id",
+            )?;
+            request.execute(named_params! {
+                ":user_id": user_id,
+                ":is_transcribe_only": is_transcribe_only,
+            })?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn set_model(&self, user_id: i64, model: Option<&str>) -> Result<(), DbError> {
+        let model = model.map(|model| model.to_string());
+
+        self.run(move |connection| {
+            let mut request =
+                connection.prepare("UPDATE users SET model = :model WHERE id = :user_id")?;
+            request.execute(named_params! {":user_id": user_id, ":model": model})?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn set_temperature(
+        &self,
+        user_id: i64,
+        temperature: Option<f32>,
+    ) -> Result<(), DbError> {
+        self.run(move |connection| {
+            let mut request = connection
+                .prepare("UPDATE users SET temperature = :temperature WHERE id = :user_id")?;
+            request.execute(named_params! {":user_id": user_id, ":temperature": temperature})?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn set_persona(&self, user_id: i64, persona: Option<&str>) -> Result<(), DbError> {
+        let persona = persona.map(|persona| persona.to_string());
+
+        self.run(move |connection| {
+            let mut request =
+                connection.prepare("UPDATE users SET persona = :persona WHERE id = :user_id")?;
+            request.execute(named_params! {":user_id": user_id, ":persona": persona})?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets the TTS speaker together with its language, `None` resets both to defaults.
+    pub async fn set_tts_voice(
+        &self,
+        user_id: i64,
+        voice: Option<&str>,
+        language: Option<&str>,
+    ) -> Result<(), DbError> {
+        let voice = voice.map(|voice| voice.to_string());
+        let language = language.map(|language| language.to_string());
+
+        self.run(move |connection| {
+            let mut request = connection.prepare(
+                "UPDATE users SET tts_voice = :voice, tts_language = :language WHERE id = :user_id",
+            )?;
+            request.execute(named_params! {
+                ":user_id": user_id,
+                ":voice": voice,
+                ":language": language,
+            })?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn set_tts_rate(&self, user_id: i64, rate: Option<f32>) -> Result<(), DbError> {
+        self.run(move |connection| {
+            let mut request =
+                connection.prepare("UPDATE users SET tts_rate = :rate WHERE id = :user_id")?;
+            request.execute(named_params! {":user_id": user_id, ":rate": rate})?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn get_personas(&self) -> Result<Vec<Persona>, DbError> {
+        self.run(|connection| {
+            let mut stmt = connection.prepare(
+                "SELECT name, system_prompt, greeting, language, voice FROM personas ORDER BY name",
+            )?;
+            let personas = stmt
+                .query_map([], DB::load_persona)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(personas)
+        })
+        .await
+    }
+
+    pub async fn get_persona(&self, name: &str) -> Result<Option<Persona>, DbError> {
+        let name = name.to_string();
+
+        self.run(move |connection| {
+            let persona = connection
+                .query_row(
+                    "SELECT name, system_prompt, greeting, language, voice FROM personas WHERE name = ?1",
+                    [name],
+                    DB::load_persona,
+                )
+                .optional()?;
+            Ok(persona)
+        })
+        .await
+    }
+
+    /// Returns `false` when a user with this username or Telegram id already exists.
+    pub async fn add_user(
+        &self,
+        user_name: &str,
+        telegram_id: Option<i64>,
+        contact_name: &str,
+        contact_form: &str,
+    ) -> Result<bool, DbError> {
+        let user_name = user_name.to_string();
+        let contact_name = contact_name.to_string();
+        let contact_form = contact_form.to_string();
+
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            let exists: bool = transaction.query_row(
+                "SELECT EXISTS(SELECT 1 FROM users WHERE username = ?1 OR telegram_id = ?2)",
+                (&user_name, telegram_id),
+                |row| row.get(0),
+            )?;
+
+            if exists {
+                return Ok(false);
+            }
+
+            transaction.execute(
+                "INSERT INTO users (username, telegram_id, contact_name, contact_form) VALUES (?1, ?2, ?3, ?4)",
+                (&user_name, telegram_id, &contact_name, &contact_form),
+            )?;
+            transaction.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    /// Returns `false` when there is no such user.
+    pub async fn remove_user(&self, user_id: i64) -> Result<bool, DbError> {
+        self.run(move |connection| {
+            let removed = connection.execute("DELETE FROM users WHERE id = ?1", [user_id])?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    /// Updates a single column of a user, `column` must come from a fixed whitelist
+    /// since it is interpolated into the query. Returns `false` when there is no such user.
+    pub async fn set_user_field(
+        &self,
+        user_id: i64,
+        column: &'static str,
+        value: Value,
+    ) -> Result<bool, DbError> {
+        self.run(move |connection| {
+            let updated = connection.execute(
+                &format!("UPDATE users SET {} = ?1 WHERE id = ?2", column),
+                (value, user_id),
+            )?;
+            Ok(updated > 0)
+        })
+        .await
+    }
+
+    /// Binds the Telegram user id to a user found by username and refreshes the display username.
+    pub async fn bind_telegram_user(
+        &self,
+        user_id: i64,
+        telegram_id: i64,
+        user_name: Option<&str>,
+    ) -> Result<(), DbError> {
+        let user_name = user_name.map(|user_name| user_name.to_string());
+
+        self.run(move |connection| {
+            connection.execute(
+                "UPDATE users SET telegram_id = ?1, username = COALESCE(?2, username) WHERE id = ?3",
+                (telegram_id, user_name, user_id),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Stores a new invite code valid for `uses` sign-ups during `ttl_seconds`.
+    pub async fn create_invite(
+        &self,
+        code: &str,
+        created_by: i64,
+        uses: u32,
+        ttl_seconds: i64,
+    ) -> Result<(), DbError> {
+        let code = code.to_string();
+
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO invites (code, created_by, uses_left, expires_at)
+                VALUES (?1, ?2, ?3, CAST(strftime('%s', 'now') AS INTEGER) + ?4)",
+                (code, created_by, uses, ttl_seconds),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Spends one use of the invite and creates the user in the same transaction.
+    /// A user added by username who is not bound yet gets bound instead, without
+    /// spending the invite, so no second row with the same username appears.
+    pub async fn redeem_invite(
+        &self,
+        code: &str,
+        telegram_id: i64,
+        user_name: &str,
+        contact_name: &str,
+        contact_form: &str,
+    ) -> Result<InviteRedemption, DbError> {
+        let code = code.to_string();
+        let user_name = user_name.to_string();
+        let contact_name = contact_name.to_string();
+        let contact_form = contact_form.to_string();
+
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            let is_bound: bool = transaction.query_row(
+                "SELECT EXISTS(SELECT 1 FROM users WHERE telegram_id = ?1)",
+                [telegram_id],
+                |row| row.get(0),
+            )?;
+            if is_bound {
+                return Ok(InviteRedemption::AlreadyMember);
+            }
+
+            // Usernames are matched case-insensitively, the same as `find_user` does.
+            let bound = transaction.execute(
+                "UPDATE users SET telegram_id = ?1, username = ?2 WHERE id = (
+                    SELECT MIN(id) FROM users WHERE telegram_id IS NULL AND username = ?2 COLLATE NOCASE
+                )",
+                (telegram_id, &user_name),
+            )?;
+            if bound > 0 {
+                transaction.commit()?;
+                return Ok(InviteRedemption::AlreadyMember);
+            }
+
+            let spent = transaction.execute(
+                "UPDATE invites SET uses_left = uses_left - 1
+                WHERE code = ?1 AND uses_left > 0 AND expires_at > CAST(strftime('%s', 'now') AS INTEGER)",
+                [&code],
+            )?;
+            if spent == 0 {
+                return Ok(InviteRedemption::Invalid);
+            }
+
+            transaction.execute(
+                "INSERT INTO users (username, telegram_id, contact_name, contact_form) VALUES (?1, ?2, ?3, ?4)",
+                (&user_name, telegram_id, &contact_name, &contact_form),
+            )?;
+            let issuer_chat_id = transaction
+                .query_row(
+                    "SELECT chat_id FROM users WHERE id = (SELECT created_by FROM invites WHERE code = ?1)",
+                    [&code],
+                    |row| row.get::<_, Option<i64>>(0),
+                )
+                .optional()?
+                .flatten()
+                .map(ChatId);
+            transaction.commit()?;
+
+            Ok(InviteRedemption::Joined { issuer_chat_id })
+        })
+        .await
+    }
+
+    pub async fn get_groups(&self) -> Result<Vec<Group>, DbError> {
+        self.run(|connection| {
+            let mut stmt =
+                connection.prepare("SELECT chat_id, title, shared_history FROM group_chats")?;
+            let rows = stmt.query_map([], |row| {
+                Ok(Group {
+                    chat_id: ChatId(row.get(0)?),
+                    title: row.get(1)?,
+                    shared_history: row.get(2)?,
                 })
-            })
-            .unwrap();
-
-        let chat_messages: Result<Vec<ChatMessage>, rusqlite::Error> =
-            message_iter.collect::<Result<Vec<_>, _>>().map(|messages| {
-                messages
-                    .into_iter()
-                    .map(|loaded_message| ChatMessage {
-                        content: loaded_message.content,
-                        role: loaded_message.role,
-                    })
-                    .collect()
-            });
+            })?;
 
-        return chat_messages;
+            let groups = rows.collect::<Result<Vec<_>, _>>()?;
+            Ok(groups)
+        })
+        .await
     }
 
-    pub fn enable_voice(&self, user_name: &str) {
-        let connection = self.get_connection();
-        let mut request = connection
-            .prepare("UPDATE users SET is_voice = 1 WHERE username = :user_name")
-            .unwrap();
-        request.execute(&[(":user_name", &user_name)]).unwrap();
+    /// Returns `false` when the group is already authorized.
+    pub async fn add_group(&self, chat_id: ChatId, title: Option<&str>) -> Result<bool, DbError> {
+        let title = title.map(|title| title.to_string());
+
+        self.run(move |connection| {
+            let added = connection.execute(
+                "INSERT OR IGNORE INTO group_chats (chat_id, title) VALUES (?1, ?2)",
+                (chat_id.0, title),
+            )?;
+            Ok(added > 0)
+        })
+        .await
+    }
+
+    /// Returns `false` when there is no such group.
+    pub async fn remove_group(&self, chat_id: ChatId) -> Result<bool, DbError> {
+        self.run(move |connection| {
+            let removed =
+                connection.execute("DELETE FROM group_chats WHERE chat_id = ?1", [chat_id.0])?;
+            Ok(removed > 0)
+        })
+        .await
     }
 
-    pub fn disable_voice(&self, user_name: &str) {
-        let connection = self.get_connection();
-        let mut request = connection
-            .prepare("UPDATE users SET is_voice = 0 WHERE username = :user_name")
-            .unwrap();
-        request.execute(&[(":user_name", &user_name)]).unwrap();
+    /// Returns `false` when there is no such group.
+    pub async fn set_group_shared_history(
+        &self,
+        chat_id: ChatId,
+        shared_history: bool,
+    ) -> Result<bool, DbError> {
+        self.run(move |connection| {
+            let updated = connection.execute(
+                "UPDATE group_chats SET shared_history = ?1 WHERE chat_id = ?2",
+                (shared_history, chat_id.0),
+            )?;
+            Ok(updated > 0)
+        })
+        .await
     }
 
-    pub fn set_user_chat_id(&self, user_name: &str, chat_id: ChatId) {
-        let connection = self.get_connection();
-        let mut request = connection
-            .prepare("UPDATE users SET chat_id = :chat_id WHERE username = :user_name")
-            .unwrap();
-        request
-            .execute(&[
-                (":user_name", &user_name),
-                (":chat_id", &chat_id.to_string().as_str()),
-            ])
-            .unwrap();
+    pub async fn set_user_chat_id(&self, user_id: i64, chat_id: ChatId) -> Result<(), DbError> {
+        self.run(move |connection| {
+            let mut request =
+                connection.prepare("UPDATE users SET chat_id = :chat_id WHERE id = :user_id")?;
+            request.execute(named_params! {":user_id": user_id, ":chat_id": chat_id.0})?;
+            Ok(())
+        })
+        .await
     }
 
-    pub fn get_users(&self) -> Result<Vec<User>, rusqlite::Error> {
-        let connection = self.get_connection();
-        let mut stmt = connection
-            .prepare("SELECT username, chat_id, contact_name, contact_form, is_voice FROM users")?;
+    pub async fn get_users(&self) -> Result<Vec<User>, DbError> {
+        self.run(|connection| {
+            let mut stmt = connection.prepare(
+                "SELECT username, chat_id, contact_name, contact_form, is_voice, language, is_echo, is_transcribe_only, model, temperature, persona, role, id, telegram_id, tts_voice, tts_rate, tts_language FROM users",
+            )?;
 
-        let users_iter = stmt
-            .query_map([], |row| {
-                let chat_id: Option<ChatId> = match row.get::<_, Option<i64>>(1) {
-                    Ok(Some(id)) => Some(ChatId(id)),
-                    _ => None,
-                };
+            let users_iter = stmt.query_map([], |row| {
+                let chat_id: Option<ChatId> = row.get::<_, Option<i64>>(1)?.map(ChatId);
+                let role_name: String = row.get(11)?;
+                // An unknown role must never grant more than read access.
+                let role = UserRole::from_str(&role_name).unwrap_or(UserRole::Readonly);
 
                 Ok(User {
                     user_name: row.get(0)?,
-                    chat_id: chat_id,
+                    chat_id,
                     contact_name: row.get(2)?,
                     contact_form: row.get(3)?,
                     is_voice: row.get(4)?,
+                    language: row.get(5)?,
+                    is_echo: row.get(6)?,
+                    is_transcribe_only: row.get(7)?,
+                    model: row.get(8)?,
+                    temperature: row.get(9)?,
+                    persona: row.get(10)?,
+                    role,
+                    id: row.get(12)?,
+                    telegram_id: row.get(13)?,
+                    tts_voice: row.get(14)?,
+                    tts_rate: row.get(15)?,
+                    tts_language: row.get(16)?,
                 })
-            })
-            .unwrap();
-
-        let users: Result<Vec<User>, rusqlite::Error> =
-            users_iter.collect::<Result<Vec<_>, _>>().map(|users| {
-                users
-                    .into_iter()
-                    .map(|row| User {
-                        user_name: row.user_name,
-                        chat_id: row.chat_id,
-                        contact_name: row.contact_name,
-                        contact_form: row.contact_form,
-                        is_voice: row.is_voice,
-                    })
-                    .collect()
+            })?;
+
+            let users = users_iter.collect::<Result<Vec<_>, _>>()?;
+            Ok(users)
+        })
+        .await
+    }
+
+    async fn set_voice(&self, user_id: i64, is_voice: bool) -> Result<(), DbError> {
+        self.run(move |connection| {
+            let mut request =
+                connection.prepare("UPDATE users SET is_voice = :is_voice WHERE id = :user_id")?;
+            request.execute(named_params! {":user_id": user_id, ":is_voice": is_voice})?;
+            Ok(())
+        })
+        .await
+    }
+
+    fn load_message(row: &Row) -> Result<LoadedMessage, rusqlite::Error> {
+        Ok(LoadedMessage {
+            id: row.get(0)?,
+            content: row.get(1)?,
+            role: row.get(2)?,
+        })
+    }
+
+    fn load_persona(row: &Row) -> Result<Persona, rusqlite::Error> {
+        Ok(Persona {
+            name: row.get(0)?,
+            system_prompt: row.get(1)?,
+            greeting: row.get(2)?,
+            language: row.get(3)?,
+            voice: row.get(4)?,
+        })
+    }
+
+    fn collect_history(
+        rows: impl Iterator<Item = Result<LoadedMessage, rusqlite::Error>>,
+    ) -> Result<Vec<HistoryMessage>, DbError> {
+        let mut history = Vec::new();
+        for loaded_message in rows {
+            let loaded_message = loaded_message?;
+            history.push(HistoryMessage {
+                id: loaded_message.id,
+                message: ChatMessage {
+                    content: loaded_message.content,
+                    role: DB::string_to_role(&loaded_message.role)?,
+                },
             });
+        }
 
-        return users;
+        Ok(history)
     }
 
-    fn get_connection(&self) -> std::sync::MutexGuard<'_, Connection> {
-        self.connection.lock().unwrap()
+    /// Runs a blocking SQLite job on a pooled connection outside of the tokio reactor threads.
+    async fn run<T, F>(&self, job: F) -> Result<T, DbError>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, DbError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut connection = pool.get()?;
+            job(&mut connection)
+        })
+        .await?
     }
 
     fn role_to_string(role: Role) -> String {
@@ -214,12 +884,12 @@ impl DB {
         }
     }
 
-    fn string_to_role(role_str: &str) -> Role {
+    fn string_to_role(role_str: &str) -> Result<Role, DbError> {
         match role_str {
-            "system" => Role::System,
-            "assistant" => Role::Assistant,
-            "user" => Role::User,
-            _ => panic!("Invalid role"),
+            "system" => Ok(Role::System),
+            "assistant" => Ok(Role::Assistant),
+            "user" => Ok(Role::User),
+            _ => Err(DbError::InvalidRole(role_str.to_string())),
         }
     }
 }
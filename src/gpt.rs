@@ -1,48 +1,117 @@
-use crate::db::{User, DB};
+use crate::db::{Conversation, HistoryMessage, Persona, Summary, User, DB, HISTORY_MAX_MESSAGES};
+use crate::tokens;
 use chatgpt::prelude::{ChatGPT, ChatGPTEngine, ModelConfigurationBuilder};
-use chatgpt::types::{ChatMessage, Role};
+use chatgpt::types::{ChatMessage, ResponseChunk, Role};
+use futures::StreamExt;
 use std::error::Error;
-use teloxide::prelude::ChatId;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
+/// Models users can pick with `/model`, the first one is the fallback default.
+pub const ALLOWED_MODELS: &[&str] = &[
+    "gpt-4o",
+    "gpt-4o-mini",
+    "gpt-4-turbo",
+    "gpt-4",
+    "gpt-3.5-turbo",
+];
+
+pub const MIN_TEMPERATURE: f32 = 0.0;
+pub const MAX_TEMPERATURE: f32 = 2.0;
+const DEFAULT_TEMPERATURE: f32 = 1.0;
+
+/// Share of the history budget after which the oldest turns are summarized.
+const SUMMARY_THRESHOLD_PERCENT: usize = 75;
+
+/// Conversations with a summary being written, so a burst of messages doesn't
+/// summarize the same turns several times.
+static PENDING_SUMMARIES: Mutex<Vec<Conversation>> = Mutex::new(Vec::new());
+
+/// Messages loaded per summary request, further trimmed to the token budget.
+const SUMMARY_BATCH_MESSAGES: i64 = HISTORY_MAX_MESSAGES;
+
+/// Holds a `PENDING_SUMMARIES` entry and removes it when dropped, even when the
+/// summary task panics.
+struct PendingSummary(Conversation);
+
+impl PendingSummary {
+    /// Returns `None` when the conversation is already being summarized.
+    fn acquire(conversation: Conversation) -> Option<Self> {
+        let mut pending = PENDING_SUMMARIES.lock().unwrap();
+        if pending.contains(&conversation) {
+            return None;
+        }
+
+        pending.push(conversation);
+        Some(PendingSummary(conversation))
+    }
+}
+
+impl Drop for PendingSummary {
+    fn drop(&mut self) {
+        if let Ok(mut pending) = PENDING_SUMMARIES.lock() {
+            pending.retain(|conversation| *conversation != self.0);
+        }
+    }
+}
+
+const SUMMARY_PROMPT: &str = "You maintain a running summary of a chat between a user and an assistant. \
+Merge the previous summary with the new messages into one concise summary that keeps names, facts, \
+decisions and open questions. Write it in the language of the conversation and answer with the summary only.";
+
+pub struct ResponseStream {
+    /// Content pieces of the answer in arrival order.
+    pub deltas: mpsc::UnboundedReceiver<String>,
+    /// Resolves once the stream is over, with the error that ended it early, if any.
+    pub completion: JoinHandle<Result<(), Box<dyn Error + Send + Sync>>>,
+}
+
+#[derive(Clone)]
 pub struct MyGPT {
     client: ChatGPT,
+    engine: ChatGPTEngine,
 }
 
 impl MyGPT {
-    pub fn new(api_key: &str) -> Self {
+    pub fn new(api_key: &str, user: &User) -> Self {
+        let model = user
+            .model
+            .as_deref()
+            .and_then(find_model)
+            .unwrap_or_else(default_model);
+        let temperature = user.temperature.unwrap_or_else(default_temperature);
+        let engine = ChatGPTEngine::Custom(model);
+
         MyGPT {
             client: ChatGPT::new_with_config(
                 api_key,
                 ModelConfigurationBuilder::default()
-                    .temperature(1.0)
-                    .engine(ChatGPTEngine::Gpt4_0314)
+                    .temperature(temperature)
+                    .engine(engine)
                     .build()
                     .unwrap(),
             )
             .unwrap(),
+            engine,
         }
     }
 
     pub async fn send_msg(
         &self,
-        chat_id: ChatId,
+        db: &DB,
+        conversation: Conversation,
         user: &User,
         message: &str,
     ) -> Result<String, Box<dyn Error + Send + Sync>> {
-        let db = DB::new();
-
-        db.save_message(chat_id, Role::User, &message);
-
-        let history = db.get_history(chat_id).unwrap();
-        let enhanced_history = MyGPT::build_history(history, &user);
-
-        print!("History: {:#?}", enhanced_history);
-
+        let enhanced_history = self
+            .prepare_history(db, conversation, user, message)
+            .await?;
         let gpt_request = self.client.send_history(&enhanced_history).await;
 
         match gpt_request {
             Ok(response) => {
-                let content = match response.message_choices.get(0) {
+                let content = match response.message_choices.first() {
                     Some(choice) => choice.message.clone().content,
                     None => return Err("No message choices found".into()),
                 };
@@ -52,26 +121,297 @@ impl MyGPT {
         }
     }
 
-    fn build_history(history: Vec<ChatMessage>, user: &User) -> Vec<ChatMessage> {
-        let mut updated_history = Vec::new();
-        let user_name = user.contact_name.to_string();
-        let user_form = user.contact_form.to_string();
+    /// Same as [`MyGPT::send_msg`] but yields the answer piece by piece as the API streams it.
+    pub async fn send_msg_streaming(
+        &self,
+        db: &DB,
+        conversation: Conversation,
+        user: &User,
+        message: &str,
+    ) -> Result<ResponseStream, Box<dyn Error + Send + Sync>> {
+        let enhanced_history = self
+            .prepare_history(db, conversation, user, message)
+            .await?;
+        let client = self.client.clone();
+        let (sender, deltas) = mpsc::unbounded_channel();
+
+        // The stream is consumed in its own task: chatgpt_rs panics on a broken
+        // stream, which then surfaces as a failed `completion` instead of a crash.
+        let completion = tokio::spawn(async move {
+            let stream = client.send_history_streaming(&enhanced_history).await?;
+            futures::pin_mut!(stream);
 
-        updated_history.push(ChatMessage {
-            content: format!(
-                "Называй меня {}. Говори со мной на {}, как будто мы с тобой давно знакомы",
-                user_name, user_form
-            )
-            .to_string(),
-            role: Role::User,
+            while let Some(chunk) = stream.next().await {
+                let delta = match chunk {
+                    ResponseChunk::Content {
+                        delta,
+                        response_index: 0,
+                    } => delta,
+                    ResponseChunk::Done => break,
+                    _ => continue,
+                };
+
+                if sender.send(delta).is_err() {
+                    break;
+                }
+            }
+
+            Ok(())
         });
 
-        updated_history.push(ChatMessage {
-            content: format!("Привет, {}! Конечно, мы можем общаться на '{}'. Как дела? Чем я могу тебе помочь? Меня зовут Валя",user_name, user_form),
-            role: Role::Assistant,
+        Ok(ResponseStream { deltas, completion })
+    }
+
+    async fn prepare_history(
+        &self,
+        db: &DB,
+        conversation: Conversation,
+        user: &User,
+        message: &str,
+    ) -> Result<Vec<ChatMessage>, Box<dyn Error + Send + Sync>> {
+        db.save_message(conversation, Role::User, message).await?;
+
+        let summary = db.get_summary(conversation).await?;
+        let last_summarized_id = summary
+            .as_ref()
+            .map_or(0, |summary| summary.last_message_id);
+        let history = db.get_history(conversation, last_summarized_id).await?;
+
+        let model = self.engine.as_ref();
+        let persona = db.get_persona(&user_persona_name(user)).await?;
+        let prelude = MyGPT::build_prelude(user, persona.as_ref(), summary.as_ref());
+        let budget =
+            tokens::history_budget(model).saturating_sub(tokens::count_messages(model, &prelude));
+
+        self.schedule_summary(db, conversation, &history, budget);
+
+        let history = history.into_iter().map(|item| item.message).collect();
+        let enhanced_history = MyGPT::build_history(prelude, history, model, budget);
+
+        log::debug!(
+            "Chat {}: sending {} messages, {} tokens",
+            conversation,
+            enhanced_history.len(),
+            tokens::count_messages(model, &enhanced_history)
+        );
+
+        Ok(enhanced_history)
+    }
+
+    /// Folds the oldest unsummarized turns into the chat summary once the history
+    /// grows close to the token budget or fills the `HISTORY_MAX_MESSAGES` window,
+    /// so they are not lost when trimmed. At most one summary per conversation is
+    /// written at a time.
+    fn schedule_summary(
+        &self,
+        db: &DB,
+        conversation: Conversation,
+        history: &[HistoryMessage],
+        budget: usize,
+    ) {
+        let model = self.engine.as_ref();
+        let messages: Vec<ChatMessage> = history.iter().map(|item| item.message.clone()).collect();
+        let used = tokens::count_messages(model, &messages);
+
+        let is_near_budget = used * 100 >= budget * SUMMARY_THRESHOLD_PERCENT;
+        // Older unsummarized rows are already out of the loaded window, the range
+        // summarized below starts at the previous summary and includes them.
+        let is_window_full = history.len() as i64 >= HISTORY_MAX_MESSAGES;
+        if !is_near_budget && !is_window_full {
+            return;
+        }
+
+        let mut kept = tokens::trim_history(model, messages, budget / 2).len();
+        if is_window_full {
+            kept = kept.min(history.len() / 2);
+        }
+        let summarized = history.len() - kept;
+        if summarized == 0 {
+            return;
+        }
+
+        let Some(pending) = PendingSummary::acquire(conversation) else {
+            return;
+        };
+
+        let up_to_id = history[summarized - 1].id;
+        let gpt = self.clone();
+        let db = db.clone();
+
+        tokio::spawn(async move {
+            let _pending = pending;
+            if let Err(error) = gpt.update_summary(&db, conversation, up_to_id).await {
+                log::error!("Failed to summarize chat {}: {}", conversation, error);
+                sentry::capture_error(&*error);
+            }
         });
+    }
+
+    /// Folds the messages up to `up_to_id` into the summary batch by batch, each
+    /// batch sized to fit the model context, and saves the summary after every
+    /// batch so a failure doesn't throw away the progress.
+    async fn update_summary(
+        &self,
+        db: &DB,
+        conversation: Conversation,
+        up_to_id: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let model = self.engine.as_ref();
+
+        loop {
+            // Read again, the summary may have moved on since the history was loaded.
+            let previous = db.get_summary(conversation).await?;
+            let after_id = previous
+                .as_ref()
+                .map_or(0, |summary| summary.last_message_id);
+            if after_id >= up_to_id {
+                return Ok(());
+            }
+
+            let mut messages = db
+                .get_history_range(conversation, after_id, up_to_id, SUMMARY_BATCH_MESSAGES)
+                .await?;
+            if messages.is_empty() {
+                return Ok(());
+            }
+
+            let previous_summary = previous.map_or("(none)".to_string(), |summary| summary.content);
+            let prompt = vec![
+                ChatMessage {
+                    content: SUMMARY_PROMPT.to_string(),
+                    role: Role::System,
+                },
+                ChatMessage {
+                    content: format!("Previous summary:\n{}\n\nNew messages:\n", previous_summary),
+                    role: Role::User,
+                },
+            ];
+            let budget = tokens::history_budget(model)
+                .saturating_sub(tokens::count_messages(model, &prompt));
+            let chat_messages: Vec<ChatMessage> =
+                messages.iter().map(|item| item.message.clone()).collect();
+            messages.truncate(tokens::fit_oldest(model, &chat_messages, budget));
 
-        updated_history.extend(history);
+            let transcript: Vec<String> = messages
+                .iter()
+                .map(|item| format!("{:?}: {}", item.message.role, item.message.content))
+                .collect();
+            let mut request = prompt;
+            request[1].content.push_str(&transcript.join("\n"));
+
+            let response = self.client.send_history(&request).await?;
+            let content = match response.message_choices.first() {
+                Some(choice) => choice.message.content.trim().to_string(),
+                None => return Err("No message choices found".into()),
+            };
+
+            let last_id = messages[messages.len() - 1].id;
+            db.save_summary(conversation, &content, last_id).await?;
+            log::info!("Chat {} summarized up to message {}", conversation, last_id);
+        }
+    }
+
+    fn build_prelude(
+        user: &User,
+        persona: Option<&Persona>,
+        summary: Option<&Summary>,
+    ) -> Vec<ChatMessage> {
+        let mut prelude = Vec::new();
+
+        if let Some(persona) = persona {
+            prelude.push(ChatMessage {
+                content: render_persona_text(&persona.system_prompt, user),
+                role: Role::System,
+            });
+
+            if let Some(greeting) = &persona.greeting {
+                prelude.push(ChatMessage {
+                    content: render_persona_text(greeting, user),
+                    role: Role::Assistant,
+                });
+            }
+        }
+
+        if let Some(summary) = summary {
+            prelude.push(ChatMessage {
+                content: format!("Summary of the earlier conversation: {}", summary.content),
+                role: Role::System,
+            });
+        }
+
+        prelude
+    }
+
+    fn build_history(
+        prelude: Vec<ChatMessage>,
+        history: Vec<ChatMessage>,
+        model: &str,
+        budget: usize,
+    ) -> Vec<ChatMessage> {
+        let mut updated_history = prelude;
+        updated_history.extend(tokens::trim_history(model, history, budget));
         updated_history
     }
 }
+
+/// Persona selected by the user or the global `DEFAULT_PERSONA` (default: `valya`).
+pub fn user_persona_name(user: &User) -> String {
+    match &user.persona {
+        Some(persona) => persona.clone(),
+        None => std::env::var("DEFAULT_PERSONA").unwrap_or_else(|_| "valya".to_string()),
+    }
+}
+
+fn render_persona_text(text: &str, user: &User) -> String {
+    text.replace("{contact_name}", &user.contact_name)
+        .replace("{contact_form}", &user.contact_form)
+}
+
+pub fn find_model(name: &str) -> Option<&'static str> {
+    ALLOWED_MODELS.iter().copied().find(|model| *model == name)
+}
+
+/// Global model from `GPT_MODEL`, falls back to the first allowed model.
+pub fn default_model() -> &'static str {
+    let model = std::env::var("GPT_MODEL").unwrap_or_default();
+
+    match find_model(&model) {
+        Some(model) => model,
+        None => {
+            if !model.is_empty() {
+                log::warn!(
+                    "GPT_MODEL {} is not allowed, using {}",
+                    model,
+                    ALLOWED_MODELS[0]
+                );
+            }
+            ALLOWED_MODELS[0]
+        }
+    }
+}
+
+/// Global temperature from `GPT_TEMPERATURE`, defaults to 1.0.
+pub fn default_temperature() -> f32 {
+    let temperature = std::env::var("GPT_TEMPERATURE").unwrap_or_default();
+    if temperature.is_empty() {
+        return DEFAULT_TEMPERATURE;
+    }
+
+    match temperature.parse::<f32>() {
+        Ok(value) if is_valid_temperature(value) => value,
+        _ => {
+            log::warn!(
+                "GPT_TEMPERATURE {} is not a number from {} to {}, using {}",
+                temperature,
+                MIN_TEMPERATURE,
+                MAX_TEMPERATURE,
+                DEFAULT_TEMPERATURE
+            );
+            DEFAULT_TEMPERATURE
+        }
+    }
+}
+
+pub fn is_valid_temperature(temperature: f32) -> bool {
+    (MIN_TEMPERATURE..=MAX_TEMPERATURE).contains(&temperature)
+}
@@ -1,4 +1,4 @@
-use crate::command::on_receive_command;
+use crate::command::{on_receive_callback_query, on_receive_command};
 use crate::db::DB;
 use crate::utils::*;
 use dotenv::dotenv;
@@ -10,11 +10,19 @@ use teloxide::{prelude::*, Bot};
 mod command;
 mod db;
 mod gpt;
+mod markdown;
+mod migrations;
+mod speech;
+mod split;
+mod stt;
+mod tokens;
+mod tts;
 mod utils;
 
-fn init_sentry() {
-    let _guard = sentry::init((
-        dbg!(std::env::var("SENTRY_DSN").unwrap_or_default()),
+/// The returned guard must live until the bot stops, dropping it closes the client.
+fn init_sentry() -> sentry::ClientInitGuard {
+    let guard = sentry::init((
+        std::env::var("SENTRY_DSN").unwrap_or_default(),
         sentry::ClientOptions {
             release: sentry::release_name!(),
             ..Default::default()
@@ -22,6 +30,7 @@ fn init_sentry() {
     ));
 
     std::env::set_var("RUST_BACKTRACE", "1");
+    guard
 }
 
 #[tokio::main]
@@ -32,43 +41,79 @@ async fn main() {
         .filter_level(LevelFilter::Info)
         .init();
 
-    let db = DB::new();
+    let db = DB::from_env().expect("Failed to open database");
 
     log::info!("Starting...");
 
-    init_sentry();
+    let _sentry = init_sentry();
 
-    db.history_migration().await;
-    db.users_migration().await;
+    if let Err(err) = db.migrate().await {
+        log::error!("Database migration failed: {}", err);
+        sentry::capture_error(&err);
+        std::process::exit(1);
+    }
 
     let bot_token = std::env::var("TELEGRAM_TOKEN").expect("TELEGRAM_TOKEN must be set.");
     let bot = Bot::new(bot_token);
 
+    let bot_username = match bot.get_me().await {
+        Ok(me) => me.username().to_string(),
+        Err(err) => {
+            log::error!("Failed to get bot info: {}", err);
+            sentry::capture_error(&err);
+            std::process::exit(1);
+        }
+    };
+
     let state = Arc::new(Mutex::new(State {
         users: Mutex::new(Vec::new()),
+        groups: Mutex::new(Vec::new()),
+        bot_username,
     }));
 
-    let users_list = db.get_users().unwrap();
+    let users_list = match db.get_users().await {
+        Ok(users_list) => users_list,
+        Err(err) => {
+            log::error!("Failed to load users: {}", err);
+            sentry::capture_error(&err);
+            std::process::exit(1);
+        }
+    };
     state.lock().unwrap().users = Mutex::new(users_list);
 
-    let handler = Update::filter_message().endpoint(
-        |bot: Bot, state: Arc<Mutex<State>>, msg: Message| async move {
-            let cloned_users = state.lock().unwrap().users.lock().unwrap().clone();
-
-            if is_command_message(msg.clone()) {
-                on_receive_command(cloned_users, bot, msg, state).await;
-            } else {
-                on_receive_message(cloned_users, bot, msg).await;
-            }
-
-            respond(())
-        },
-    );
+    if let Err(err) = refresh_groups(&db, &state).await {
+        log::error!("Failed to load groups: {}", err);
+        sentry::capture_error(&err);
+        std::process::exit(1);
+    }
+
+    let handler = dptree::entry()
+        .branch(Update::filter_message().endpoint(
+            |bot: Bot, state: Arc<Mutex<State>>, db: DB, msg: Message| async move {
+                let cloned_users = state.lock().unwrap().users.lock().unwrap().clone();
+
+                if is_command_message(msg.clone()) {
+                    on_receive_command(cloned_users, bot, msg, state, db).await;
+                } else {
+                    on_receive_message(cloned_users, bot, msg, state, db).await;
+                }
+
+                respond(())
+            },
+        ))
+        .branch(Update::filter_callback_query().endpoint(
+            |bot: Bot, state: Arc<Mutex<State>>, db: DB, query: CallbackQuery| async move {
+                let cloned_users = state.lock().unwrap().users.lock().unwrap().clone();
+                on_receive_callback_query(cloned_users, bot, query, state, db).await;
+
+                respond(())
+            },
+        ));
 
     let cloned_state = Arc::clone(&state);
 
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![cloned_state])
+        .dependencies(dptree::deps![cloned_state, db])
         .enable_ctrlc_handler()
         .build()
         .dispatch()
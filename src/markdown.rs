@@ -0,0 +1,382 @@
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag};
+use std::ops::Range;
+
+/// Converts GPT's CommonMark answer into the HTML subset Telegram understands.
+/// Elements Telegram has no tags for are approximated: headings become bold lines,
+/// list items get bullets or numbers and table cells are separated by `|`.
+pub fn to_telegram_html(markdown: &str) -> String {
+    let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES;
+    let mut html = String::with_capacity(markdown.len());
+    // Next number of every open list, `None` for bullet lists.
+    let mut lists: Vec<Option<u64>> = Vec::new();
+
+    for event in Parser::new_ext(markdown, options) {
+        match event {
+            Event::Start(tag) => match tag {
+                Tag::Paragraph | Tag::TableRow | Tag::FootnoteDefinition(_) => {}
+                Tag::Heading(..) | Tag::Strong => html.push_str("<b>"),
+                Tag::BlockQuote => html.push_str("<blockquote>"),
+                Tag::CodeBlock(CodeBlockKind::Fenced(language)) if !language.is_empty() => {
+                    let language = language.split_whitespace().next().unwrap_or_default();
+                    html.push_str(&format!(
+                        "<pre><code class=\"language-{}\">",
+                        escape(language)
+                    ));
+                }
+                Tag::CodeBlock(_) => html.push_str("<pre><code>"),
+                Tag::List(start) => {
+                    end_line(&mut html);
+                    lists.push(start);
+                }
+                Tag::Item => {
+                    html.push_str(&"    ".repeat(lists.len().saturating_sub(1)));
+                    match lists.last_mut() {
+                        Some(Some(number)) => {
+                            html.push_str(&format!("{}. ", number));
+                            *number += 1;
+                        }
+                        _ => html.push_str("• "),
+                    }
+                }
+                Tag::Table(_) => {}
+                Tag::TableHead => html.push_str("<b>"),
+                Tag::TableCell => {
+                    let is_first_cell =
+                        html.is_empty() || html.ends_with('\n') || html.ends_with("<b>");
+                    if !is_first_cell {
+                        html.push_str(" | ");
+                    }
+                }
+                Tag::Emphasis => html.push_str("<i>"),
+                Tag::Strikethrough => html.push_str("<s>"),
+                Tag::Link(_, url, _) | Tag::Image(_, url, _) => {
+                    html.push_str(&format!("<a href=\"{}\">", escape(&url)));
+                }
+            },
+            Event::End(tag) => match tag {
+                Tag::Paragraph | Tag::Table(_) => end_block(&mut html),
+                Tag::Heading(..) => {
+                    html.push_str("</b>");
+                    end_block(&mut html);
+                }
+                Tag::BlockQuote => {
+                    trim_line_breaks(&mut html);
+                    html.push_str("</blockquote>");
+                    end_block(&mut html);
+                }
+                Tag::CodeBlock(_) => {
+                    trim_line_breaks(&mut html);
+                    html.push_str("</code></pre>");
+                    end_block(&mut html);
+                }
+                Tag::List(_) => {
+                    lists.pop();
+                    if lists.is_empty() {
+                        end_block(&mut html);
+                    }
+                }
+                Tag::Item => {
+                    trim_line_breaks(&mut html);
+                    html.push('\n');
+                }
+                Tag::TableHead => html.push_str("</b>\n"),
+                Tag::TableRow => html.push('\n'),
+                Tag::TableCell | Tag::FootnoteDefinition(_) => {}
+                Tag::Strong => html.push_str("</b>"),
+                Tag::Emphasis => html.push_str("</i>"),
+                Tag::Strikethrough => html.push_str("</s>"),
+                Tag::Link(..) | Tag::Image(..) => html.push_str("</a>"),
+            },
+            Event::Text(text) | Event::Html(text) => html.push_str(&escape(&text)),
+            Event::Code(code) => html.push_str(&format!("<code>{}</code>", escape(&code))),
+            Event::FootnoteReference(label) => html.push_str(&format!("[{}]", escape(&label))),
+            Event::SoftBreak | Event::HardBreak => html.push('\n'),
+            Event::Rule => {
+                html.push_str("———");
+                end_block(&mut html);
+            }
+            Event::TaskListMarker(checked) => html.push_str(if checked { "☑ " } else { "☐ " }),
+        }
+    }
+
+    html.trim().to_string()
+}
+
+/// Escapes the characters Telegram treats as markup in HTML mode.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn trim_line_breaks(html: &mut String) {
+    let trimmed_len = html.trim_end_matches('\n').len();
+    html.truncate(trimmed_len);
+}
+
+fn end_line(html: &mut String) {
+    if !html.is_empty() && !html.ends_with('\n') {
+        html.push('\n');
+    }
+}
+
+/// Blocks are separated by an empty line.
+fn end_block(html: &mut String) {
+    trim_line_breaks(html);
+    if !html.is_empty() {
+        html.push_str("\n\n");
+    }
+}
+
+/// Fenced code block taken out of a message to be sent as a file.
+pub struct CodeAttachment {
+    pub file_name: String,
+    pub code: String,
+}
+
+/// Replaces fenced code blocks longer than `min_chars` characters with a short
+/// reference and returns them as attachments named after their language tag.
+pub fn extract_code_blocks(markdown: &str, min_chars: usize) -> (String, Vec<CodeAttachment>) {
+    let mut text = String::with_capacity(markdown.len());
+    let mut attachments: Vec<CodeAttachment> = Vec::new();
+    let mut position = 0;
+    let mut block: Option<(Range<usize>, String, String)> = None;
+
+    for (event, range) in Parser::new(markdown).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(language))) => {
+                block = Some((range, language.to_string(), String::new()));
+            }
+            Event::Text(code) => {
+                if let Some((_, _, content)) = block.as_mut() {
+                    content.push_str(&code);
+                }
+            }
+            Event::End(Tag::CodeBlock(_)) => {
+                let (range, language, code) = match block.take() {
+                    Some(block) => block,
+                    None => continue,
+                };
+                if code.chars().count() <= min_chars {
+                    continue;
+                }
+
+                let extension = file_extension(&language);
+                let count = attachments
+                    .iter()
+                    .filter(|attachment| attachment.file_name.ends_with(&format!(".{}", extension)))
+                    .count();
+                let file_name = match count {
+                    0 => format!("snippet.{}", extension),
+                    _ => format!("snippet_{}.{}", count + 1, extension),
+                };
+
+                text.push_str(&markdown[position..range.start]);
+                text.push_str(&format!("_(code sent as {})_", file_name));
+                if markdown[range.clone()].ends_with('\n') {
+                    text.push('\n');
+                }
+                position = range.end;
+                attachments.push(CodeAttachment { file_name, code });
+            }
+            _ => {}
+        }
+    }
+
+    text.push_str(&markdown[position..]);
+    (text, attachments)
+}
+
+fn file_extension(language: &str) -> String {
+    let language = language
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+
+    let extension = match language.as_str() {
+        "rust" => "rs",
+        "python" => "py",
+        "javascript" | "node" => "js",
+        "typescript" => "ts",
+        "bash" | "shell" | "zsh" | "console" => "sh",
+        "c++" => "cpp",
+        "csharp" | "c#" => "cs",
+        "kotlin" => "kt",
+        "ruby" => "rb",
+        "yaml" => "yml",
+        "markdown" => "md",
+        "golang" => "go",
+        "text" | "plaintext" | "" => "txt",
+        other if other.len() <= 10 && other.chars().all(|c| c.is_ascii_alphanumeric()) => other,
+        _ => "txt",
+    };
+
+    extension.to_string()
+}
+
+/// Part of an answer, in the original markdown.
+pub enum Segment {
+    Prose(String),
+    Code(String),
+}
+
+/// Splits the answer into prose and code blocks (fenced or indented), in order.
+pub fn segments(markdown: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut position = 0;
+
+    let mut indented_code: Option<String> = None;
+
+    for (event, range) in Parser::new(markdown).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                push_prose(&mut segments, &markdown[position..range.start]);
+                position = range.end;
+
+                match kind {
+                    CodeBlockKind::Fenced(_) => {
+                        segments.push(Segment::Code(markdown[range].trim_end().to_string()))
+                    }
+                    CodeBlockKind::Indented => indented_code = Some(String::new()),
+                }
+            }
+            Event::Text(code) => {
+                if let Some(indented_code) = indented_code.as_mut() {
+                    indented_code.push_str(&code);
+                }
+            }
+            // Indented code is fenced so it is still shown as code once sent on its own.
+            Event::End(Tag::CodeBlock(_)) => {
+                if let Some(code) = indented_code.take() {
+                    segments.push(Segment::Code(format!("```\n{}\n```", code.trim_end())));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    push_prose(&mut segments, &markdown[position..]);
+    segments
+}
+
+fn push_prose(segments: &mut Vec<Segment>, prose: &str) {
+    if !prose.trim().is_empty() {
+        segments.push(Segment::Prose(prose.trim().to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_text() {
+        assert_eq!(
+            to_telegram_html("a < b & \"c\""),
+            "a &lt; b &amp; &quot;c&quot;"
+        );
+    }
+
+    #[test]
+    fn escapes_code() {
+        assert_eq!(
+            to_telegram_html("Use `x<y && z`."),
+            "Use <code>x&lt;y &amp;&amp; z</code>."
+        );
+        assert_eq!(
+            to_telegram_html("```rust\nif a < b && c == \"d\" {}\n```"),
+            "<pre><code class=\"language-rust\">if a &lt; b &amp;&amp; c == &quot;d&quot; {}</code></pre>"
+        );
+    }
+
+    #[test]
+    fn escapes_links() {
+        assert_eq!(
+            to_telegram_html("[a<b](https://example.com/?a=1&b=\"2\")"),
+            "<a href=\"https://example.com/?a=1&amp;b=&quot;2&quot;\">a&lt;b</a>"
+        );
+    }
+
+    #[test]
+    fn approximates_unsupported_elements() {
+        assert_eq!(
+            to_telegram_html("# Title\n\n**bold** and _it_"),
+            "<b>Title</b>\n\n<b>bold</b> and <i>it</i>"
+        );
+        assert_eq!(
+            to_telegram_html("- one\n  - two\n- three"),
+            "• one\n    • two\n• three"
+        );
+        assert_eq!(
+            to_telegram_html("| a | b |\n|---|---|\n| 1 | 2 |"),
+            "<b>a | b</b>\n1 | 2"
+        );
+    }
+
+    #[test]
+    fn extracts_long_code_blocks() {
+        let rust = "fn main() {\n    println!(\"Hello\");\n}";
+        let markdown = format!(
+            "First:\n\n```rust\n{rust}\n```\n\nShort:\n\n```rust\nx\n```\n\nSecond:\n\n```rust\n{rust}\n```\n\nScript:\n\n```python\nprint('Hello, world')\n```\n"
+        );
+        let (text, attachments) = extract_code_blocks(&markdown, 10);
+
+        let names: Vec<&str> = attachments
+            .iter()
+            .map(|attachment| attachment.file_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["snippet.rs", "snippet_2.rs", "snippet.py"]);
+        assert_eq!(attachments[0].code, format!("{}\n", rust));
+        assert_eq!(
+            text,
+            "First:\n\n_(code sent as snippet.rs)_\n\nShort:\n\n```rust\nx\n```\n\nSecond:\n\n_(code sent as snippet_2.rs)_\n\nScript:\n\n_(code sent as snippet.py)_\n"
+        );
+    }
+
+    #[test]
+    fn maps_languages_to_extensions() {
+        assert_eq!(file_extension("rust"), "rs");
+        assert_eq!(file_extension("Python title=\"main\""), "py");
+        assert_eq!(file_extension("C++"), "cpp");
+        assert_eq!(file_extension("toml"), "toml");
+        assert_eq!(file_extension(""), "txt");
+        assert_eq!(file_extension("not/a-language"), "txt");
+    }
+
+    fn describe(segments: Vec<Segment>) -> Vec<(&'static str, String)> {
+        segments
+            .into_iter()
+            .map(|segment| match segment {
+                Segment::Prose(prose) => ("prose", prose),
+                Segment::Code(code) => ("code", code),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_prose_and_code() {
+        let markdown = "Run this:\n\n```sh\ncargo run\n```\n\nOr the old way:\n\n    make\n    make install\n\nDone.";
+
+        assert_eq!(
+            describe(segments(markdown)),
+            vec![
+                ("prose", "Run this:".to_string()),
+                ("code", "```sh\ncargo run\n```".to_string()),
+                ("prose", "Or the old way:".to_string()),
+                ("code", "```\nmake\nmake install\n```".to_string()),
+                ("prose", "Done.".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_prose_without_code_whole() {
+        assert_eq!(
+            describe(segments("  Just text.\n\nTwo paragraphs.  ")),
+            vec![("prose", "Just text.\n\nTwo paragraphs.".to_string())]
+        );
+        assert!(segments(" \n ").is_empty());
+    }
+}
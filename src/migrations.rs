@@ -0,0 +1,339 @@
+use rusqlite::{Connection, Result, Transaction};
+
+type Migration = fn(&Transaction) -> Result<()>;
+
+/// Ordered schema migrations. Append new entries with the next version number,
+/// never edit or reorder the ones that already shipped.
+const MIGRATIONS: &[(u32, &str, Migration)] = &[
+    (1, "create chat_history", create_chat_history),
+    (2, "create users", create_users),
+    (3, "add users.language", add_users_language),
+    (
+        4,
+        "add users transcript settings",
+        add_users_transcript_settings,
+    ),
+    (5, "create chat_summaries", create_chat_summaries),
+    (6, "add users model settings", add_users_model_settings),
+    (7, "create personas", create_personas),
+    (8, "add users.role", add_users_role),
+    (9, "add users.telegram_id", add_users_telegram_id),
+    (10, "create invites", create_invites),
+    (11, "create group_chats", create_group_chats),
+    (12, "add chat_history.thread_id", add_chat_history_thread_id),
+    (13, "add users tts settings", add_users_tts_settings),
+];
+
+/// Applies every pending migration inside a single transaction.
+pub fn run(connection: &mut Connection) -> Result<()> {
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version     INTEGER PRIMARY KEY,
+            name        TEXT NOT NULL,
+            applied_at  TEXT DEFAULT CURRENT_TIMESTAMP
+        )",
+        (),
+    )?;
+
+    let transaction = connection.transaction()?;
+    let current_version: u32 = transaction.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        (),
+        |row| row.get(0),
+    )?;
+
+    for (version, name, migration) in MIGRATIONS.iter() {
+        if *version <= current_version {
+            continue;
+        }
+
+        migration(&transaction)?;
+        transaction.execute(
+            "INSERT INTO schema_version (version, name) VALUES (?1, ?2)",
+            (version, name),
+        )?;
+        log::info!("Migration {} [{}] applied", version, name);
+    }
+
+    transaction.commit()
+}
+
+// Databases created before versioned migrations already have some of these
+// tables and columns, so the early migrations must tolerate existing objects.
+
+fn create_chat_history(transaction: &Transaction) -> Result<()> {
+    transaction.execute(
+        "CREATE TABLE IF NOT EXISTS chat_history (
+            id          INTEGER PRIMARY KEY,
+            chat_id     INTEGER  NOT NULL,
+            message     TEXT NOT NULL,
+            role        VARCHAR(20) NOT NULL,
+            created_at  TEXT DEFAULT CURRENT_TIMESTAMP
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn create_users(transaction: &Transaction) -> Result<()> {
+    transaction.execute(
+        "CREATE TABLE IF NOT EXISTS users (
+            id              INTEGER PRIMARY KEY,
+            username        VARCHAR(100) NOT NULL,
+            chat_id         INTEGER DEFAULT NULL,
+            contact_name    VARCHAR(100) NOT NULL,
+            contact_form    VARCHAR(20) NOT NULL,
+            is_voice        TINNYINT(1) DEFAULT 0
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn add_users_language(transaction: &Transaction) -> Result<()> {
+    add_column(transaction, "users", "language", "VARCHAR(10) DEFAULT NULL")
+}
+
+fn add_users_transcript_settings(transaction: &Transaction) -> Result<()> {
+    add_column(transaction, "users", "is_echo", "TINNYINT(1) DEFAULT 0")?;
+    add_column(
+        transaction,
+        "users",
+        "is_transcribe_only",
+        "TINNYINT(1) DEFAULT 0",
+    )
+}
+
+fn create_chat_summaries(transaction: &Transaction) -> Result<()> {
+    transaction.execute(
+        "CREATE TABLE chat_summaries (
+            chat_id         INTEGER PRIMARY KEY,
+            summary         TEXT NOT NULL,
+            last_message_id INTEGER NOT NULL,
+            updated_at      TEXT DEFAULT CURRENT_TIMESTAMP
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn add_users_model_settings(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "ALTER TABLE users ADD COLUMN model VARCHAR(50) DEFAULT NULL;
+        ALTER TABLE users ADD COLUMN temperature REAL DEFAULT NULL;",
+    )
+}
+
+fn create_personas(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "CREATE TABLE personas (
+            id              INTEGER PRIMARY KEY,
+            name            VARCHAR(50) NOT NULL UNIQUE,
+            system_prompt   TEXT NOT NULL,
+            greeting        TEXT DEFAULT NULL,
+            language        VARCHAR(10) DEFAULT NULL,
+            voice           VARCHAR(100) DEFAULT NULL
+        );
+        ALTER TABLE users ADD COLUMN persona VARCHAR(50) DEFAULT NULL;",
+    )?;
+
+    transaction.execute(
+        "INSERT INTO personas (name, system_prompt, greeting, language) VALUES (?1, ?2, ?3, ?4)",
+        (
+            "valya",
+            "Тебя зовут Валя. Называй собеседника {contact_name} и говори с ним на '{contact_form}', как будто вы давно знакомы.",
+            "Привет, {contact_name}! Как дела? Чем я могу тебе помочь?",
+            "ru",
+        ),
+    )?;
+
+    transaction.execute(
+        "INSERT INTO personas (name, system_prompt, greeting, language) VALUES (?1, ?2, ?3, ?4)",
+        (
+            "assistant",
+            "You are a friendly and helpful assistant. Address the user as {contact_name}.",
+            None::<&str>,
+            "en",
+        ),
+    )?;
+
+    Ok(())
+}
+
+/// Everybody could broadcast before roles existed, the oldest user keeps that
+/// ability as the first admin and everyone else becomes a regular user.
+fn add_users_role(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "ALTER TABLE users ADD COLUMN role VARCHAR(20) NOT NULL DEFAULT 'user';
+        UPDATE users SET role = 'admin' WHERE id = (SELECT MIN(id) FROM users);",
+    )
+}
+
+/// In private chats the chat id equals the user id, so users who already talked
+/// to the bot are bound right away. The rest are bound on their next message.
+/// Rows sharing a username got the same chat id, only the oldest one is bound.
+fn add_users_telegram_id(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "ALTER TABLE users ADD COLUMN telegram_id INTEGER DEFAULT NULL;
+        UPDATE users SET telegram_id = chat_id WHERE chat_id > 0
+            AND id IN (SELECT MIN(id) FROM users WHERE chat_id > 0 GROUP BY chat_id);
+        CREATE UNIQUE INDEX users_telegram_id ON users (telegram_id);",
+    )
+}
+
+fn create_invites(transaction: &Transaction) -> Result<()> {
+    transaction.execute(
+        "CREATE TABLE invites (
+            id          INTEGER PRIMARY KEY,
+            code        VARCHAR(64) NOT NULL UNIQUE,
+            created_by  INTEGER NOT NULL,
+            uses_left   INTEGER NOT NULL,
+            expires_at  INTEGER NOT NULL,
+            created_at  TEXT DEFAULT CURRENT_TIMESTAMP
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Group members may have their own history, so history and summaries are keyed
+/// by `member_id` too, `0` stands for a whole chat.
+fn create_group_chats(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "CREATE TABLE group_chats (
+            chat_id         INTEGER PRIMARY KEY,
+            title           TEXT DEFAULT NULL,
+            shared_history  TINNYINT(1) NOT NULL DEFAULT 1,
+            created_at      TEXT DEFAULT CURRENT_TIMESTAMP
+        );
+        ALTER TABLE chat_history ADD COLUMN member_id INTEGER NOT NULL DEFAULT 0;
+        CREATE INDEX chat_history_conversation ON chat_history (chat_id, member_id, id);
+        CREATE TABLE chat_summaries_new (
+            chat_id         INTEGER NOT NULL,
+            member_id       INTEGER NOT NULL DEFAULT 0,
+            summary         TEXT NOT NULL,
+            last_message_id INTEGER NOT NULL,
+            updated_at      TEXT DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (chat_id, member_id)
+        );
+        INSERT INTO chat_summaries_new (chat_id, summary, last_message_id, updated_at)
+            SELECT chat_id, summary, last_message_id, updated_at FROM chat_summaries;
+        DROP TABLE chat_summaries;
+        ALTER TABLE chat_summaries_new RENAME TO chat_summaries;",
+    )
+}
+
+/// Forum topics have separate histories, `0` stands for messages outside of topics.
+fn add_chat_history_thread_id(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "ALTER TABLE chat_history ADD COLUMN thread_id INTEGER NOT NULL DEFAULT 0;
+        DROP INDEX chat_history_conversation;
+        CREATE INDEX chat_history_conversation ON chat_history (chat_id, thread_id, member_id, id);
+        CREATE TABLE chat_summaries_new (
+            chat_id         INTEGER NOT NULL,
+            thread_id       INTEGER NOT NULL DEFAULT 0,
+            member_id       INTEGER NOT NULL DEFAULT 0,
+            summary         TEXT NOT NULL,
+            last_message_id INTEGER NOT NULL,
+            updated_at      TEXT DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (chat_id, thread_id, member_id)
+        );
+        INSERT INTO chat_summaries_new (chat_id, member_id, summary, last_message_id, updated_at)
+            SELECT chat_id, member_id, summary, last_message_id, updated_at FROM chat_summaries;
+        DROP TABLE chat_summaries;
+        ALTER TABLE chat_summaries_new RENAME TO chat_summaries;",
+    )
+}
+
+fn add_users_tts_settings(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "ALTER TABLE users ADD COLUMN tts_voice VARCHAR(64) DEFAULT NULL;
+        ALTER TABLE users ADD COLUMN tts_rate REAL DEFAULT NULL;
+        ALTER TABLE users ADD COLUMN tts_language VARCHAR(10) DEFAULT NULL;",
+    )
+}
+
+fn add_column(
+    transaction: &Transaction,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    if has_column(transaction, table, column)? {
+        return Ok(());
+    }
+
+    transaction.execute(
+        &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+        (),
+    )?;
+    Ok(())
+}
+
+fn has_column(transaction: &Transaction, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = transaction.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map((), |row| row.get::<_, String>(1))?;
+
+    for name in columns {
+        if name? == column {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binds_oldest_row_of_shared_username() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        // Users table as created before versioned migrations.
+        connection
+            .execute_batch(
+                "CREATE TABLE users (
+                    id              INTEGER PRIMARY KEY,
+                    username        VARCHAR(100) NOT NULL,
+                    chat_id         INTEGER DEFAULT NULL,
+                    contact_name    VARCHAR(100) NOT NULL,
+                    contact_form    VARCHAR(20) NOT NULL,
+                    is_voice        TINNYINT(1) DEFAULT 0
+                );
+                INSERT INTO users (username, chat_id, contact_name, contact_form)
+                    VALUES ('alice', 42, 'Alice', 'ты');
+                INSERT INTO users (username, chat_id, contact_name, contact_form)
+                    VALUES ('alice', 42, 'Alice', 'вы');
+                INSERT INTO users (username, chat_id, contact_name, contact_form)
+                    VALUES ('bob', NULL, 'Bob', 'ты');",
+            )
+            .unwrap();
+
+        run(&mut connection).unwrap();
+
+        let mut stmt = connection
+            .prepare("SELECT id, telegram_id FROM users ORDER BY id")
+            .unwrap();
+        let users: Vec<(i64, Option<i64>)> = stmt
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(users, vec![(1, Some(42)), (2, None), (3, None)]);
+    }
+
+    #[test]
+    fn runs_on_empty_database_once() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        run(&mut connection).unwrap();
+        run(&mut connection).unwrap();
+
+        let version: u32 = connection
+            .query_row("SELECT MAX(version) FROM schema_version", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.last().unwrap().0);
+    }
+}
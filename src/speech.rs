@@ -0,0 +1,1128 @@
+use pulldown_cmark::{Event, Options, Parser, Tag};
+
+/// Language numbers, dates and abbreviations are spelled out in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Language {
+    English,
+    Russian,
+}
+
+impl Language {
+    /// Picks the language by its ISO-639-1 code, or by the alphabet of the text
+    /// when the code is missing or not supported.
+    pub fn detect(code: Option<&str>, text: &str) -> Self {
+        match code.map(|code| code.trim().to_lowercase()).as_deref() {
+            Some("ru") => return Language::Russian,
+            Some("en") => return Language::English,
+            _ => {}
+        }
+
+        let cyrillic = text
+            .chars()
+            .filter(|c| matches!(c, 'а'..='я' | 'А'..='Я' | 'ё' | 'Ё'))
+            .count();
+        let latin = text.chars().filter(|c| c.is_ascii_alphabetic()).count();
+
+        if cyrillic >= latin && cyrillic > 0 {
+            Language::Russian
+        } else {
+            Language::English
+        }
+    }
+}
+
+/// Turns a GPT answer into text a TTS engine reads naturally: markdown syntax is
+/// stripped, URLs become "link", numbers, dates and common abbreviations are
+/// spelled out and emoji are dropped.
+pub fn normalize(text: &str, language: Language) -> String {
+    let text = remove_emoji(text);
+    let text = strip_markdown(&text);
+    let text = replace_urls(&text, language);
+    let text = expand_abbreviations(&text, language);
+    let text = expand_dates(&text, language);
+    let text = expand_numbers(&text, language);
+
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Keeps only the readable text, every block ends with a full stop so the
+/// engine pauses between headings, list items and paragraphs.
+fn strip_markdown(markdown: &str) -> String {
+    let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES;
+    let mut text = String::with_capacity(markdown.len());
+
+    for event in Parser::new_ext(markdown, options) {
+        match event {
+            Event::Text(part) | Event::Code(part) => text.push_str(&part),
+            Event::SoftBreak | Event::HardBreak => text.push(' '),
+            Event::End(
+                Tag::Paragraph
+                | Tag::Heading(..)
+                | Tag::Item
+                | Tag::CodeBlock(_)
+                | Tag::TableHead
+                | Tag::TableRow,
+            ) => end_sentence(&mut text),
+            Event::End(Tag::TableCell) => text.push_str(", "),
+            _ => {}
+        }
+    }
+
+    text
+}
+
+fn end_sentence(text: &mut String) {
+    let trimmed_len = text.trim_end_matches([' ', ',']).len();
+    text.truncate(trimmed_len);
+
+    if text.is_empty() || text.ends_with('\n') {
+        return;
+    }
+    if !text.ends_with(['.', '!', '?', '…', ':', ';']) {
+        text.push('.');
+    }
+    text.push('\n');
+}
+
+/// Splits the text into words with the whitespace that follows them.
+fn words(text: &str) -> impl Iterator<Item = (&str, &str)> {
+    text.split_inclusive(char::is_whitespace).map(|word| {
+        let trimmed = word.trim_end();
+        (trimmed, &word[trimmed.len()..])
+    })
+}
+
+/// Word without the surrounding brackets and punctuation.
+fn word_core(word: &str) -> (&str, &str, &str) {
+    let start = word.len() - word.trim_start_matches(['(', '[', '«', '"']).len();
+    let rest = &word[start..];
+    let core = rest.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '»', '"']);
+
+    (&word[..start], core, &rest[core.len()..])
+}
+
+fn replace_urls(text: &str, language: Language) -> String {
+    let link = match language {
+        Language::English => "link",
+        Language::Russian => "ссылка",
+    };
+
+    words(text)
+        .map(|(word, space)| {
+            let (prefix, core, suffix) = word_core(word);
+            let lowercase = core.to_lowercase();
+            let is_url = ["http://", "https://", "www."]
+                .iter()
+                .any(|scheme| lowercase.starts_with(scheme));
+
+            if is_url {
+                format!("{}{}{}{}", prefix, link, suffix, space)
+            } else {
+                format!("{}{}", word, space)
+            }
+        })
+        .collect()
+}
+
+const ENGLISH_ABBREVIATIONS: &[(&str, &str)] = &[
+    ("e.g.", "for example"),
+    ("i.e.", "that is"),
+    ("etc.", "et cetera"),
+    ("vs.", "versus"),
+    ("approx.", "approximately"),
+    ("Mr.", "Mister"),
+    ("Mrs.", "Missus"),
+    ("Dr.", "Doctor"),
+];
+
+const RUSSIAN_ABBREVIATIONS: &[(&str, &str)] = &[
+    ("т.е.", "то есть"),
+    ("т. е.", "то есть"),
+    ("т.д.", "так далее"),
+    ("т. д.", "так далее"),
+    ("т.п.", "тому подобное"),
+    ("т. п.", "тому подобное"),
+    ("т.к.", "так как"),
+    ("т. к.", "так как"),
+    ("напр.", "например"),
+    ("др.", "другие"),
+    ("руб.", "рублей"),
+    ("тыс.", "тысяч"),
+    ("млн", "миллионов"),
+    ("млрд", "миллиардов"),
+];
+
+fn expand_abbreviations(text: &str, language: Language) -> String {
+    let abbreviations = match language {
+        Language::English => ENGLISH_ABBREVIATIONS,
+        Language::Russian => RUSSIAN_ABBREVIATIONS,
+    };
+
+    abbreviations
+        .iter()
+        .fold(text.to_string(), |text, (abbreviation, expansion)| {
+            replace_word(&text, abbreviation, expansion)
+        })
+}
+
+/// Replaces `from` only where it is a whole word. The full stop of an abbreviation
+/// that ends a paragraph is kept.
+fn replace_word(text: &str, from: &str, to: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut position = 0;
+
+    for (start, _) in text.match_indices(from) {
+        if start < position {
+            continue;
+        }
+        let end = start + from.len();
+        let starts_word = !text[..start]
+            .chars()
+            .next_back()
+            .is_some_and(char::is_alphanumeric);
+        let ends_word = from.ends_with('.')
+            || !text[end..]
+                .chars()
+                .next()
+                .is_some_and(char::is_alphanumeric);
+
+        if starts_word && ends_word {
+            result.push_str(&text[position..start]);
+            result.push_str(to);
+            let ends_paragraph = end == text.len() || text[end..].starts_with('\n');
+            if from.ends_with('.') && ends_paragraph {
+                result.push('.');
+            }
+            position = end;
+        }
+    }
+
+    result.push_str(&text[position..]);
+    result
+}
+
+/// Spells out `31.12.2024` and `2024-12-31` dates.
+fn expand_dates(text: &str, language: Language) -> String {
+    words(text)
+        .map(|(word, space)| {
+            let (prefix, core, suffix) = word_core(word);
+            match parse_date(core) {
+                Some((day, month, year)) => {
+                    let date = match language {
+                        Language::English => english_date(day, month, year),
+                        Language::Russian => russian_date(day, month, year),
+                    };
+                    format!("{}{}{}{}", prefix, date, suffix, space)
+                }
+                None => format!("{}{}", word, space),
+            }
+        })
+        .collect()
+}
+
+fn parse_date(word: &str) -> Option<(u64, usize, u64)> {
+    let is_number = |part: &str, lengths: &[usize]| {
+        lengths.contains(&part.len()) && part.chars().all(|c| c.is_ascii_digit())
+    };
+
+    let parts: Vec<&str> = word.split('.').collect();
+    let (day, month, year) = match parts.as_slice() {
+        [day, month, year] if is_number(day, &[1, 2]) && is_number(month, &[1, 2]) => {
+            (*day, *month, *year)
+        }
+        _ => match word.split('-').collect::<Vec<_>>().as_slice() {
+            [year, month, day] if is_number(month, &[2]) && is_number(day, &[2]) => {
+                (*day, *month, *year)
+            }
+            _ => return None,
+        },
+    };
+    if !is_number(year, &[4]) {
+        return None;
+    }
+
+    let (day, month, year) = (day.parse().ok()?, month.parse().ok()?, year.parse().ok()?);
+    if !(1..=31).contains(&day) || !(1..=12).contains(&month) {
+        return None;
+    }
+
+    Some((day, month, year))
+}
+
+fn expand_numbers(text: &str, language: Language) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut result = String::with_capacity(text.len());
+    let mut index = 0;
+
+    while index < chars.len() {
+        let previous = index.checked_sub(1).map(|previous| chars[previous]);
+        let starts_number =
+            chars[index].is_ascii_digit() && !previous.is_some_and(char::is_alphanumeric);
+        if !starts_number {
+            result.push(chars[index]);
+            index += 1;
+            continue;
+        }
+
+        if let Some((spoken, end)) = read_sequence(&chars, index, language) {
+            result.push_str(&spoken);
+            index = end;
+            continue;
+        }
+
+        let (number, end) = read_number(&chars, index, language);
+        if chars.get(end).is_some_and(|c| c.is_alphabetic()) {
+            // Part of a name like `4o` or `3D`, left for the engine.
+            result.extend(&chars[index..end]);
+            index = end;
+            continue;
+        }
+
+        let mut preceding = result.chars().rev();
+        let is_negative = preceding.next().is_some_and(|c| matches!(c, '-' | '−'))
+            && !preceding.next().is_some_and(char::is_alphanumeric);
+        if is_negative {
+            result.pop();
+            result.push_str(match language {
+                Language::English => "minus ",
+                Language::Russian => "минус ",
+            });
+        }
+
+        result.push_str(&number.spell(language));
+        index = end;
+
+        if chars.get(index) == Some(&'%') {
+            result.push(' ');
+            result.push_str(&number.percent(language));
+            index += 1;
+        }
+    }
+
+    result
+}
+
+/// Reads digit groups that are not a single number: `10:30` times, dotted
+/// versions like `1.2.3` and phone numbers like `8-800-555-35-35`.
+fn read_sequence(chars: &[char], start: usize, language: Language) -> Option<(String, usize)> {
+    for separator in [':', '.', '-'] {
+        let (groups, end) = digit_groups(chars, start, separator);
+        if chars.get(end).is_some_and(|c| c.is_alphanumeric()) {
+            continue;
+        }
+
+        let spoken = match separator {
+            ':' => spell_time(&groups, language),
+            '.' if groups.len() >= 3 => {
+                let point = match language {
+                    Language::English => " point ",
+                    Language::Russian => " точка ",
+                };
+                let numbers: Vec<String> = groups
+                    .iter()
+                    .map(|group| {
+                        let number = Number {
+                            integer: group.clone(),
+                            fraction: None,
+                        };
+                        number.spell(language)
+                    })
+                    .collect();
+                Some(numbers.join(point))
+            }
+            '-' if groups.len() >= 3 => Some(
+                groups
+                    .iter()
+                    .map(|group| spell_digits(group, language))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            _ => None,
+        };
+        if let Some(spoken) = spoken {
+            return Some((spoken, end));
+        }
+    }
+
+    None
+}
+
+/// Reads digit groups joined by `separator`, a separator not followed by digits
+/// is left out.
+fn digit_groups(chars: &[char], start: usize, separator: char) -> (Vec<String>, usize) {
+    let mut groups = Vec::new();
+    let mut index = start;
+
+    loop {
+        let length = digit_count(chars, index);
+        groups.push(chars[index..index + length].iter().collect());
+        index += length;
+        if chars.get(index) != Some(&separator) || digit_count(chars, index + 1) == 0 {
+            break;
+        }
+        index += 1;
+    }
+
+    (groups, index)
+}
+
+fn digit_count(chars: &[char], index: usize) -> usize {
+    chars[index.min(chars.len())..]
+        .iter()
+        .take_while(|c| c.is_ascii_digit())
+        .count()
+}
+
+/// Reads `hh:mm` the way a clock is read aloud, `None` for anything else.
+fn spell_time(groups: &[String], language: Language) -> Option<String> {
+    let [hours, minutes] = groups else {
+        return None;
+    };
+    let (hour, minute) = (hours.parse::<u64>().ok()?, minutes.parse::<u64>().ok()?);
+    if hours.len() > 2 || minutes.len() != 2 || hour > 23 || minute > 59 {
+        return None;
+    }
+
+    let spoken = match language {
+        Language::English => {
+            let hour_words = english_cardinal(hour);
+            match minute {
+                0 if hour <= 12 => format!("{} o'clock", hour_words),
+                0 => format!("{} hundred", hour_words),
+                1..=9 => format!("{} oh {}", hour_words, english_cardinal(minute)),
+                _ => format!("{} {}", hour_words, english_cardinal(minute)),
+            }
+        }
+        Language::Russian => {
+            let hour_words = russian_cardinal(hour, Gender::Masculine);
+            match minute {
+                0 => format!("{} ноль ноль", hour_words),
+                1..=9 => format!(
+                    "{} ноль {}",
+                    hour_words,
+                    russian_cardinal(minute, Gender::Feminine)
+                ),
+                _ => format!(
+                    "{} {}",
+                    hour_words,
+                    russian_cardinal(minute, Gender::Feminine)
+                ),
+            }
+        }
+    };
+
+    Some(spoken)
+}
+
+struct Number {
+    integer: String,
+    fraction: Option<String>,
+}
+
+/// Reads digits starting at `start`, with `1 000` style thousand groups and a
+/// decimal part. In English `1,000` is a thousand, in Russian `1,5` is a fraction.
+fn read_number(chars: &[char], start: usize, language: Language) -> (Number, usize) {
+    let digits_at = |index: usize| digit_count(chars, index);
+
+    let mut end = start + digits_at(start);
+    let mut integer: String = chars[start..end].iter().collect();
+
+    loop {
+        let separator = chars.get(end).copied();
+        let is_group_separator = matches!(separator, Some(' ' | '\u{a0}' | '\u{202f}'))
+            || (separator == Some(',') && language == Language::English);
+        if !is_group_separator || digits_at(end + 1) != 3 {
+            break;
+        }
+        integer.extend(&chars[end + 1..end + 4]);
+        end += 4;
+    }
+
+    let mut fraction = None;
+    let separator = chars.get(end).copied();
+    let is_decimal_point =
+        separator == Some('.') || (separator == Some(',') && language == Language::Russian);
+    if is_decimal_point {
+        let length = digits_at(end + 1);
+        if length > 0 {
+            fraction = Some(chars[end + 1..end + 1 + length].iter().collect());
+            end += 1 + length;
+        }
+    }
+
+    (Number { integer, fraction }, end)
+}
+
+impl Number {
+    fn spell(&self, language: Language) -> String {
+        let integer = match self.integer.parse::<u64>() {
+            Ok(value) if !self.integer.starts_with('0') || self.integer.len() == 1 => value,
+            // Too long for a number or a code with leading zeros, read digit by digit.
+            _ => return spell_digits(&self.integer, language),
+        };
+
+        match (&self.fraction, language) {
+            (None, Language::English) => english_cardinal(integer),
+            (None, Language::Russian) => russian_cardinal(integer, Gender::Masculine),
+            (Some(fraction), Language::English) => format!(
+                "{} point {}",
+                english_cardinal(integer),
+                spell_digits(fraction, language)
+            ),
+            (Some(fraction), Language::Russian) => russian_fraction(integer, fraction),
+        }
+    }
+
+    fn percent(&self, language: Language) -> String {
+        match language {
+            Language::English => "percent".to_string(),
+            Language::Russian if self.fraction.is_some() => "процента".to_string(),
+            Language::Russian => {
+                let count = self.integer.parse::<u64>().unwrap_or(5);
+                russian_plural(count, ["процент", "процента", "процентов"]).to_string()
+            }
+        }
+    }
+}
+
+fn spell_digits(digits: &str, language: Language) -> String {
+    digits
+        .chars()
+        .filter_map(|c| c.to_digit(10))
+        .map(|digit| match language {
+            Language::English => english_cardinal(digit as u64),
+            Language::Russian => russian_cardinal(digit as u64, Gender::Masculine),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Drops emoji together with the space before them when punctuation follows.
+fn remove_emoji(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut after_emoji = false;
+
+    for c in text.chars() {
+        let is_emoji = matches!(c as u32,
+            0x1F000..=0x1FAFF // pictographs, emoticons, flags
+            | 0x2600..=0x27BF // symbols and dingbats
+            | 0x2300..=0x23FF // clocks and media buttons
+            | 0x2B00..=0x2BFF // stars and arrows
+            | 0xFE00..=0xFE0F // variation selectors
+            | 0x200D // zero width joiner
+            | 0xE0020..=0xE007F // tag sequences
+        );
+        if is_emoji {
+            after_emoji = true;
+            continue;
+        }
+
+        if after_emoji && matches!(c, '.' | ',' | '!' | '?' | ';' | ':') {
+            let trimmed_len = result.trim_end_matches(' ').len();
+            result.truncate(trimmed_len);
+        }
+        if !c.is_whitespace() {
+            after_emoji = false;
+        }
+        result.push(c);
+    }
+
+    result
+}
+
+const ENGLISH_ONES: [&str; 20] = [
+    "zero",
+    "one",
+    "two",
+    "three",
+    "four",
+    "five",
+    "six",
+    "seven",
+    "eight",
+    "nine",
+    "ten",
+    "eleven",
+    "twelve",
+    "thirteen",
+    "fourteen",
+    "fifteen",
+    "sixteen",
+    "seventeen",
+    "eighteen",
+    "nineteen",
+];
+
+const ENGLISH_TENS: [&str; 10] = [
+    "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+];
+
+const ENGLISH_SCALES: [(u64, &str); 4] = [
+    (1_000_000_000_000, "trillion"),
+    (1_000_000_000, "billion"),
+    (1_000_000, "million"),
+    (1_000, "thousand"),
+];
+
+const ENGLISH_MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+fn english_cardinal(number: u64) -> String {
+    if number < 20 {
+        return ENGLISH_ONES[number as usize].to_string();
+    }
+    if number < 100 {
+        return match number % 10 {
+            0 => ENGLISH_TENS[(number / 10) as usize].to_string(),
+            ones => format!(
+                "{}-{}",
+                ENGLISH_TENS[(number / 10) as usize],
+                ENGLISH_ONES[ones as usize]
+            ),
+        };
+    }
+    if number < 1000 {
+        return match number % 100 {
+            0 => format!("{} hundred", ENGLISH_ONES[(number / 100) as usize]),
+            rest => format!(
+                "{} hundred {}",
+                ENGLISH_ONES[(number / 100) as usize],
+                english_cardinal(rest)
+            ),
+        };
+    }
+
+    let (scale, name) = ENGLISH_SCALES
+        .iter()
+        .find(|(scale, _)| number >= *scale)
+        .copied()
+        .unwrap_or(ENGLISH_SCALES[3]);
+    match number % scale {
+        0 => format!("{} {}", english_cardinal(number / scale), name),
+        rest => format!(
+            "{} {} {}",
+            english_cardinal(number / scale),
+            name,
+            english_cardinal(rest)
+        ),
+    }
+}
+
+fn english_ordinal(number: u64) -> String {
+    let cardinal = english_cardinal(number);
+    let split = cardinal.rfind([' ', '-']).map_or(0, |index| index + 1);
+    let (head, last) = cardinal.split_at(split);
+
+    let last = match last {
+        "one" => "first".to_string(),
+        "two" => "second".to_string(),
+        "three" => "third".to_string(),
+        "five" => "fifth".to_string(),
+        "eight" => "eighth".to_string(),
+        "nine" => "ninth".to_string(),
+        "twelve" => "twelfth".to_string(),
+        other if other.ends_with('y') => format!("{}ieth", &other[..other.len() - 1]),
+        other => format!("{}th", other),
+    };
+
+    format!("{}{}", head, last)
+}
+
+/// Years are read in pairs of digits: nineteen ninety-nine, twenty twenty-four.
+fn english_year(year: u64) -> String {
+    if !(1000..10000).contains(&year) || (2000..2010).contains(&year) || year.is_multiple_of(1000) {
+        return english_cardinal(year);
+    }
+
+    let (century, rest) = (year / 100, year % 100);
+    match rest {
+        0 => format!("{} hundred", english_cardinal(century)),
+        1..=9 => format!(
+            "{} oh {}",
+            english_cardinal(century),
+            english_cardinal(rest)
+        ),
+        _ => format!("{} {}", english_cardinal(century), english_cardinal(rest)),
+    }
+}
+
+fn english_date(day: u64, month: usize, year: u64) -> String {
+    format!(
+        "{} {}, {}",
+        ENGLISH_MONTHS[month - 1],
+        english_ordinal(day),
+        english_year(year)
+    )
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Gender {
+    Masculine,
+    Feminine,
+}
+
+const RUSSIAN_ONES: [&str; 20] = [
+    "ноль",
+    "один",
+    "два",
+    "три",
+    "четыре",
+    "пять",
+    "шесть",
+    "семь",
+    "восемь",
+    "девять",
+    "десять",
+    "одиннадцать",
+    "двенадцать",
+    "тринадцать",
+    "четырнадцать",
+    "пятнадцать",
+    "шестнадцать",
+    "семнадцать",
+    "восемнадцать",
+    "девятнадцать",
+];
+
+const RUSSIAN_TENS: [&str; 10] = [
+    "",
+    "",
+    "двадцать",
+    "тридцать",
+    "сорок",
+    "пятьдесят",
+    "шестьдесят",
+    "семьдесят",
+    "восемьдесят",
+    "девяносто",
+];
+
+const RUSSIAN_HUNDREDS: [&str; 10] = [
+    "",
+    "сто",
+    "двести",
+    "триста",
+    "четыреста",
+    "пятьсот",
+    "шестьсот",
+    "семьсот",
+    "восемьсот",
+    "девятьсот",
+];
+
+/// Scale, its gender and the forms for one, two to four and five and more.
+const RUSSIAN_SCALES: [(u64, Gender, [&str; 3]); 4] = [
+    (
+        1_000_000_000_000,
+        Gender::Masculine,
+        ["триллион", "триллиона", "триллионов"],
+    ),
+    (
+        1_000_000_000,
+        Gender::Masculine,
+        ["миллиард", "миллиарда", "миллиардов"],
+    ),
+    (
+        1_000_000,
+        Gender::Masculine,
+        ["миллион", "миллиона", "миллионов"],
+    ),
+    (1_000, Gender::Feminine, ["тысяча", "тысячи", "тысяч"]),
+];
+
+/// Ordinals in the neuter nominative case, used for days: «первое».
+const RUSSIAN_DAY_ORDINALS: [&str; 20] = [
+    "",
+    "первое",
+    "второе",
+    "третье",
+    "четвёртое",
+    "пятое",
+    "шестое",
+    "седьмое",
+    "восьмое",
+    "девятое",
+    "десятое",
+    "одиннадцатое",
+    "двенадцатое",
+    "тринадцатое",
+    "четырнадцатое",
+    "пятнадцатое",
+    "шестнадцатое",
+    "семнадцатое",
+    "восемнадцатое",
+    "девятнадцатое",
+];
+
+/// Ordinals in the masculine genitive case, used for years: «первого».
+const RUSSIAN_YEAR_ORDINALS: [&str; 20] = [
+    "",
+    "первого",
+    "второго",
+    "третьего",
+    "четвёртого",
+    "пятого",
+    "шестого",
+    "седьмого",
+    "восьмого",
+    "девятого",
+    "десятого",
+    "одиннадцатого",
+    "двенадцатого",
+    "тринадцатого",
+    "четырнадцатого",
+    "пятнадцатого",
+    "шестнадцатого",
+    "семнадцатого",
+    "восемнадцатого",
+    "девятнадцатого",
+];
+
+const RUSSIAN_YEAR_TENS_ORDINALS: [&str; 10] = [
+    "",
+    "",
+    "двадцатого",
+    "тридцатого",
+    "сорокового",
+    "пятидесятого",
+    "шестидесятого",
+    "семидесятого",
+    "восьмидесятого",
+    "девяностого",
+];
+
+const RUSSIAN_YEAR_HUNDREDS_ORDINALS: [&str; 10] = [
+    "",
+    "сотого",
+    "двухсотого",
+    "трёхсотого",
+    "четырёхсотого",
+    "пятисотого",
+    "шестисотого",
+    "семисотого",
+    "восьмисотого",
+    "девятисотого",
+];
+
+const RUSSIAN_DAY_TENS_ORDINALS: [&str; 10] =
+    ["", "", "двадцатое", "тридцатое", "", "", "", "", "", ""];
+
+const RUSSIAN_MONTHS: [&str; 12] = [
+    "января",
+    "февраля",
+    "марта",
+    "апреля",
+    "мая",
+    "июня",
+    "июля",
+    "августа",
+    "сентября",
+    "октября",
+    "ноября",
+    "декабря",
+];
+
+fn russian_plural(count: u64, forms: [&str; 3]) -> &str {
+    match (count % 10, count % 100) {
+        (_, 11..=14) => forms[2],
+        (1, _) => forms[0],
+        (2..=4, _) => forms[1],
+        _ => forms[2],
+    }
+}
+
+fn russian_cardinal(number: u64, gender: Gender) -> String {
+    if number == 0 {
+        return RUSSIAN_ONES[0].to_string();
+    }
+
+    let mut words: Vec<String> = Vec::new();
+    let mut rest = number;
+
+    for (scale, scale_gender, forms) in RUSSIAN_SCALES {
+        let count = rest / scale;
+        if count > 0 {
+            words.push(russian_cardinal(count, scale_gender));
+            words.push(russian_plural(count, forms).to_string());
+            rest %= scale;
+        }
+    }
+
+    if rest >= 100 {
+        words.push(RUSSIAN_HUNDREDS[(rest / 100) as usize].to_string());
+        rest %= 100;
+    }
+    if rest >= 20 {
+        words.push(RUSSIAN_TENS[(rest / 10) as usize].to_string());
+        rest %= 10;
+    }
+    if rest > 0 {
+        let word = match (rest, gender) {
+            (1, Gender::Feminine) => "одна",
+            (2, Gender::Feminine) => "две",
+            _ => RUSSIAN_ONES[rest as usize],
+        };
+        words.push(word.to_string());
+    }
+
+    words.join(" ")
+}
+
+/// Reads `3,14` as «три целых четырнадцать сотых», longer fractions digit by digit.
+fn russian_fraction(integer: u64, fraction: &str) -> String {
+    let denominators = [
+        ["десятая", "десятых", "десятых"],
+        ["сотая", "сотых", "сотых"],
+        ["тысячная", "тысячных", "тысячных"],
+    ];
+    let whole = format!(
+        "{} {}",
+        russian_cardinal(integer, Gender::Feminine),
+        russian_plural(integer, ["целая", "целых", "целых"])
+    );
+
+    match (
+        fraction.parse::<u64>(),
+        denominators.get(fraction.len().wrapping_sub(1)),
+    ) {
+        (Ok(value), Some(forms)) => format!(
+            "{} {} {}",
+            whole,
+            russian_cardinal(value, Gender::Feminine),
+            russian_plural(value, *forms)
+        ),
+        _ => format!(
+            "{} запятая {}",
+            russian_cardinal(integer, Gender::Masculine),
+            spell_digits(fraction, Language::Russian)
+        ),
+    }
+}
+
+fn russian_ordinal(number: u64, ones: &[&str; 20], tens: &[&str; 10]) -> String {
+    match number {
+        0..=19 => ones[number as usize].to_string(),
+        _ if number.is_multiple_of(10) => tens[(number / 10) as usize].to_string(),
+        _ => format!(
+            "{} {}",
+            RUSSIAN_TENS[(number / 10) as usize],
+            ones[(number % 10) as usize]
+        ),
+    }
+}
+
+/// «две тысячи двадцать четвёртого»: every part is a cardinal but the last one.
+fn russian_year(year: u64) -> String {
+    if year == 0 || year >= 10_000 {
+        return russian_cardinal(year, Gender::Masculine);
+    }
+
+    let thousands = year / 1000 * 1000;
+    let hundreds = year % 1000 / 100 * 100;
+    let rest = year % 100;
+    // Years are read «тысяча девятьсот», not «одна тысяча девятьсот».
+    let cardinal = |number: u64| {
+        let words = russian_cardinal(number, Gender::Masculine);
+        match words.strip_prefix("одна ") {
+            Some(words) => words.to_string(),
+            None => words,
+        }
+    };
+
+    if rest > 0 {
+        let head = cardinal(thousands + hundreds);
+        let tail = russian_ordinal(rest, &RUSSIAN_YEAR_ORDINALS, &RUSSIAN_YEAR_TENS_ORDINALS);
+        return match thousands + hundreds {
+            0 => tail,
+            _ => format!("{} {}", head, tail),
+        };
+    }
+
+    if hundreds > 0 {
+        let tail = RUSSIAN_YEAR_HUNDREDS_ORDINALS[(hundreds / 100) as usize];
+        return match thousands {
+            0 => tail.to_string(),
+            _ => format!("{} {}", cardinal(thousands), tail),
+        };
+    }
+
+    match year / 1000 {
+        1 => "тысячного",
+        2 => "двухтысячного",
+        3 => "трёхтысячного",
+        4 => "четырёхтысячного",
+        5 => "пятитысячного",
+        6 => "шеститысячного",
+        7 => "семитысячного",
+        8 => "восьмитысячного",
+        _ => "девятитысячного",
+    }
+    .to_string()
+}
+
+fn russian_date(day: u64, month: usize, year: u64) -> String {
+    format!(
+        "{} {} {} года",
+        russian_ordinal(day, &RUSSIAN_DAY_ORDINALS, &RUSSIAN_DAY_TENS_ORDINALS),
+        RUSSIAN_MONTHS[month - 1],
+        russian_year(year)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Case agreement with the surrounding words is out of scope, the Russian
+    // inputs keep numbers where the nominative case is correct.
+
+    #[test]
+    fn detects_language() {
+        assert_eq!(Language::detect(Some("ru"), "hello"), Language::Russian);
+        assert_eq!(Language::detect(Some("EN"), "привет"), Language::English);
+        assert_eq!(Language::detect(None, "Привет, GPT!"), Language::Russian);
+        assert_eq!(Language::detect(Some("de"), "Hello"), Language::English);
+    }
+
+    #[test]
+    fn strips_markdown() {
+        let text =
+            "## Plan\n\n- **first** step\n- `second` step\n\nSee [the docs](https://example.com).";
+        assert_eq!(
+            normalize(text, Language::English),
+            "Plan.\nfirst step.\nsecond step.\nSee the docs."
+        );
+    }
+
+    #[test]
+    fn replaces_urls() {
+        assert_eq!(
+            normalize(
+                "Open https://example.com/a?b=1, then www.rust-lang.org.",
+                Language::English
+            ),
+            "Open link, then link."
+        );
+        assert_eq!(
+            normalize("Подробнее здесь (https://example.com).", Language::Russian),
+            "Подробнее здесь (ссылка)."
+        );
+    }
+
+    #[test]
+    fn expands_english_numbers() {
+        assert_eq!(
+            normalize("I have 21 apples and 1,250 pears.", Language::English),
+            "I have twenty-one apples and one thousand two hundred fifty pears."
+        );
+        assert_eq!(
+            normalize("It is -5 outside, 3.14 or 40% off.", Language::English),
+            "It is minus five outside, three point one four or forty percent off."
+        );
+        assert_eq!(
+            normalize("Steps 1,2,3 take 1.5 hours.", Language::English),
+            "Steps one,two,three take one point five hours."
+        );
+        assert_eq!(
+            normalize("Use gpt-4o or mp3 files.", Language::English),
+            "Use gpt-4o or mp3 files."
+        );
+        assert_eq!(
+            normalize("Update to 1.2.3 by 10:30 or 9:05.", Language::English),
+            "Update to one point two point three by ten thirty or nine oh five."
+        );
+        assert_eq!(
+            normalize("Call 8-800-555-35-35 before 18:00.", Language::English),
+            "Call eight, eight zero zero, five five five, three five, three five before eighteen hundred."
+        );
+    }
+
+    #[test]
+    fn expands_russian_numbers() {
+        assert_eq!(
+            normalize("Ответ: 21, а не 2 000.", Language::Russian),
+            "Ответ: двадцать один, а не две тысячи."
+        );
+        assert_eq!(
+            normalize("Скидка 3%, а курс 3,5.", Language::Russian),
+            "Скидка три процента, а курс три целых пять десятых."
+        );
+        assert_eq!(
+            normalize("Население 1 000 000 человек.", Language::Russian),
+            "Население один миллион человек."
+        );
+        assert_eq!(
+            normalize("Версия 1.2.3 вышла в 10:30.", Language::Russian),
+            "Версия один точка два точка три вышла в десять тридцать."
+        );
+        assert_eq!(
+            normalize("Звоните 8-800-555-35-35 в 9:05.", Language::Russian),
+            "Звоните восемь, восемь ноль ноль, пять пять пять, три пять, три пять в девять ноль пять."
+        );
+    }
+
+    #[test]
+    fn expands_dates() {
+        assert_eq!(
+            normalize("Released on 2024-03-05.", Language::English),
+            "Released on March fifth, twenty twenty-four."
+        );
+        assert_eq!(
+            normalize("Since 01.01.2000 and 1999-12-31", Language::English),
+            "Since January first, two thousand and December thirty-first, nineteen ninety-nine."
+        );
+        assert_eq!(
+            normalize("Дата выхода: 05.03.2024.", Language::Russian),
+            "Дата выхода: пятое марта две тысячи двадцать четвёртого года."
+        );
+        assert_eq!(
+            normalize("Даты: 31.12.1900 и 01.01.2000", Language::Russian),
+            "Даты: тридцать первое декабря тысяча девятисотого года и первое января двухтысячного года."
+        );
+    }
+
+    #[test]
+    fn expands_abbreviations() {
+        assert_eq!(
+            normalize("Fruits, e.g. apples vs. pears etc.", Language::English),
+            "Fruits, for example apples versus pears et cetera."
+        );
+        assert_eq!(
+            normalize("Фрукты, т.е. яблоки, груши и т. д.", Language::Russian),
+            "Фрукты, то есть яблоки, груши и так далее."
+        );
+        assert_eq!(
+            normalize("Стоит 5 млн руб.", Language::Russian),
+            "Стоит пять миллионов рублей."
+        );
+        assert_eq!(
+            normalize("Длина 10 см.", Language::Russian),
+            "Длина десять см."
+        );
+    }
+
+    #[test]
+    fn drops_emoji() {
+        assert_eq!(
+            normalize("Done ✅ 👍🏻 great 🇷🇺!", Language::English),
+            "Done great!"
+        );
+        assert_eq!(normalize("Привет 😊", Language::Russian), "Привет.");
+    }
+}
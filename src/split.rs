@@ -0,0 +1,530 @@
+/// Maximal length of a Telegram text message, in UTF-16 code units.
+pub const TELEGRAM_MESSAGE_LIMIT: usize = 4096;
+
+/// A piece of the message that is never split further, with the separator
+/// that joins it to the previous piece of the same part.
+struct Atom {
+    separator: &'static str,
+    text: String,
+}
+
+/// Splits the text into parts of at most `limit` UTF-16 code units. Parts break on
+/// paragraph boundaries first, then on lines, sentences and words. Code blocks are
+/// kept whole when they fit, otherwise every part gets its own opening and closing fence.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    if text_len(text) <= limit {
+        return vec![text.to_string()];
+    }
+
+    let mut atoms = Vec::new();
+    for block in blocks(text) {
+        let separator = if atoms.is_empty() { "" } else { "\n\n" };
+
+        match block {
+            Block::Text(text) => push_text(&mut atoms, &text, separator, 0, limit),
+            Block::Code {
+                opening,
+                fence,
+                lines,
+            } => push_code(&mut atoms, opening, fence, &lines, separator, limit),
+        }
+    }
+
+    let mut parts = Vec::new();
+    let mut current = String::new();
+
+    for atom in atoms {
+        if current.is_empty() {
+            current = atom.text;
+        } else if text_len(&current) + text_len(atom.separator) + text_len(&atom.text) <= limit {
+            current.push_str(atom.separator);
+            current.push_str(&atom.text);
+        } else {
+            parts.push(std::mem::replace(&mut current, atom.text));
+        }
+    }
+
+    if !current.is_empty() {
+        parts.push(current);
+    }
+
+    parts
+}
+
+/// Same as [`split_message`], but a part whose `render` output is over `limit` is
+/// split again with a smaller limit, for markup that grows once rendered.
+pub fn split_rendered(text: &str, limit: usize, render: impl Fn(&str) -> String) -> Vec<String> {
+    // Below this the part is sent as is and left to the plain text fallback.
+    let min_limit = (limit / 4).max(1);
+
+    split_message(text, limit)
+        .into_iter()
+        .flat_map(|part| {
+            let mut part_limit = limit;
+            loop {
+                let parts = split_message(&part, part_limit);
+                let longest = parts
+                    .iter()
+                    .map(|part| text_len(&render(part)))
+                    .max()
+                    .unwrap_or(0);
+                if longest <= limit || part_limit <= min_limit {
+                    return parts;
+                }
+                part_limit = (part_limit * limit / longest).clamp(min_limit, part_limit - 1);
+            }
+        })
+        .collect()
+}
+
+fn text_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+enum Block<'a> {
+    Text(String),
+    Code {
+        opening: &'a str,
+        fence: Fence,
+        lines: Vec<&'a str>,
+    },
+}
+
+/// Character and length of a code fence. A block is closed only by a fence of the
+/// same character that is at least as long, as in CommonMark.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Fence {
+    marker: char,
+    len: usize,
+}
+
+impl Fence {
+    fn closing(&self) -> String {
+        self.marker.to_string().repeat(self.len)
+    }
+}
+
+/// Paragraphs separated by blank lines and fenced code blocks, a code block
+/// without the closing fence runs to the end of the text.
+fn blocks(text: &str) -> Vec<Block<'_>> {
+    let mut blocks = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut code: Option<(&str, Fence, Vec<&str>)> = None;
+
+    for line in text.lines() {
+        if let Some((opening, fence, lines)) = code.as_mut() {
+            if is_closing_fence(line, *fence) {
+                blocks.push(Block::Code {
+                    opening,
+                    fence: *fence,
+                    lines: std::mem::take(lines),
+                });
+                code = None;
+            } else {
+                lines.push(line);
+            }
+            continue;
+        }
+
+        let fence = opening_fence(line);
+        if fence.is_none() && !line.trim().is_empty() {
+            paragraph.push(line);
+            continue;
+        }
+
+        if !paragraph.is_empty() {
+            blocks.push(Block::Text(paragraph.join("\n")));
+            paragraph.clear();
+        }
+        if let Some(fence) = fence {
+            code = Some((line, fence, Vec::new()));
+        }
+    }
+
+    if !paragraph.is_empty() {
+        blocks.push(Block::Text(paragraph.join("\n")));
+    }
+    if let Some((opening, fence, lines)) = code {
+        blocks.push(Block::Code {
+            opening,
+            fence,
+            lines,
+        });
+    }
+
+    blocks
+}
+
+/// A run of at least three backticks or tildes, backtick fences can't have
+/// backticks in the info string.
+fn opening_fence(line: &str) -> Option<Fence> {
+    let line = line.trim_start();
+    let marker = line.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    let len = line.chars().take_while(|c| *c == marker).count();
+
+    if len < 3 || (marker == '`' && line[len..].contains('`')) {
+        return None;
+    }
+
+    Some(Fence { marker, len })
+}
+
+fn is_closing_fence(line: &str, fence: Fence) -> bool {
+    let line = line.trim();
+    let len = line.chars().take_while(|c| *c == fence.marker).count();
+
+    len >= fence.len && line[len..].is_empty()
+}
+
+/// Splits text that doesn't fit into lines, then sentences, then words and,
+/// as a last resort, into chunks of characters.
+fn push_text(
+    atoms: &mut Vec<Atom>,
+    text: &str,
+    separator: &'static str,
+    level: usize,
+    limit: usize,
+) {
+    if text_len(text) <= limit {
+        atoms.push(Atom {
+            separator,
+            text: text.to_string(),
+        });
+        return;
+    }
+
+    let (pieces, inner_separator): (Vec<&str>, &'static str) = match level {
+        0 => (text.lines().collect(), "\n"),
+        1 => (sentences(text), " "),
+        2 => (text.split_whitespace().collect(), " "),
+        _ => {
+            for (index, chunk) in hard_split(text, limit).into_iter().enumerate() {
+                atoms.push(Atom {
+                    separator: if index == 0 { separator } else { "" },
+                    text: chunk,
+                });
+            }
+            return;
+        }
+    };
+
+    for (index, piece) in pieces.into_iter().enumerate() {
+        let separator = if index == 0 {
+            separator
+        } else {
+            inner_separator
+        };
+        push_text(atoms, piece, separator, level + 1, limit);
+    }
+}
+
+fn push_code(
+    atoms: &mut Vec<Atom>,
+    opening: &str,
+    fence: Fence,
+    lines: &[&str],
+    separator: &'static str,
+    limit: usize,
+) {
+    let closing = fence.closing();
+    let whole = format!("{}\n{}\n{}", opening, lines.join("\n"), closing);
+    if text_len(&whole) <= limit {
+        atoms.push(Atom {
+            separator,
+            text: whole,
+        });
+        return;
+    }
+
+    let opening = part_opening(opening, fence, limit);
+    let wrap = |body: &str| format!("{}\n{}\n{}", opening, body, closing);
+    // Room for the code itself once both fences and their line breaks are added.
+    let room = limit
+        .saturating_sub(text_len(&opening) + text_len(&closing) + 2)
+        .max(1);
+    let mut chunks: Vec<Vec<String>> = vec![Vec::new()];
+    // Length of the current chunk with a line break after every line.
+    let mut used = 0;
+
+    for line in lines {
+        for piece in hard_split(line, room) {
+            let piece_len = text_len(&piece) + 1;
+            if used + piece_len > room + 1 && used > 0 {
+                chunks.push(Vec::new());
+                used = 0;
+            }
+
+            used += piece_len;
+            chunks.last_mut().unwrap().push(piece);
+        }
+    }
+
+    for (index, chunk) in chunks.iter().enumerate() {
+        atoms.push(Atom {
+            separator: if index == 0 { separator } else { "\n" },
+            text: wrap(&chunk.join("\n")),
+        });
+    }
+}
+
+/// Opening line repeated in every part of a split code block. When the fences
+/// would take more than half of the part, the info string is cut down to the
+/// language, or dropped.
+fn part_opening(opening: &str, fence: Fence, limit: usize) -> String {
+    let closing = fence.closing();
+    let fits = |line: &str| (text_len(line) + text_len(&closing) + 2) * 2 <= limit;
+    if fits(opening) {
+        return opening.to_string();
+    }
+
+    let info = opening.trim_start().trim_start_matches(fence.marker);
+    if let Some(language) = info.split_whitespace().next() {
+        let line = format!("{}{}", closing, language);
+        if fits(&line) {
+            return line;
+        }
+    }
+
+    closing
+}
+
+/// Sentences keep their final punctuation, the whitespace after it is dropped.
+fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        let is_end = matches!(c, '.' | '!' | '?' | '…')
+            && chars.peek().is_some_and(|(_, next)| next.is_whitespace());
+
+        if is_end {
+            let end = index + c.len_utf8();
+            sentences.push(text[start..end].trim());
+            start = end;
+        }
+    }
+
+    if !text[start..].trim().is_empty() {
+        sentences.push(text[start..].trim());
+    }
+
+    sentences
+}
+
+fn hard_split(text: &str, limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+
+    for c in text.chars() {
+        if current_len + c.len_utf16() > limit && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+        current.push(c);
+        current_len += c.len_utf16();
+    }
+
+    chunks.push(current);
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_within_limit(parts: &[String], limit: usize) {
+        for part in parts {
+            assert!(text_len(part) <= limit, "part is too long: {:?}", part);
+        }
+    }
+
+    /// Every part is either plain text or a single code block with both fences.
+    fn assert_fenced(parts: &[String], opening: &str, closing: &str) {
+        for part in parts {
+            let lines: Vec<&str> = part.lines().collect();
+            assert_eq!(lines.first(), Some(&opening), "part: {:?}", part);
+            assert_eq!(lines.last(), Some(&closing), "part: {:?}", part);
+        }
+    }
+
+    fn code_body(parts: &[String]) -> Vec<String> {
+        parts
+            .iter()
+            .flat_map(|part| {
+                let lines: Vec<&str> = part.lines().collect();
+                lines[1..lines.len() - 1]
+                    .iter()
+                    .map(|line| line.to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_short_text_whole() {
+        assert_eq!(split_message("Hello\n\nworld", 100), vec!["Hello\n\nworld"]);
+    }
+
+    #[test]
+    fn splits_between_paragraphs_in_order() {
+        let paragraphs: Vec<String> = (0..20)
+            .map(|index| format!("Paragraph {}.", index))
+            .collect();
+        let text = paragraphs.join("\n\n");
+        let parts = split_message(&text, 50);
+
+        assert!(parts.len() > 1);
+        assert_within_limit(&parts, 50);
+        assert_eq!(parts.join("\n\n"), text);
+    }
+
+    #[test]
+    fn splits_long_sentences_and_words() {
+        let text = format!("{} {}", "word ".repeat(40).trim(), "x".repeat(120));
+        let parts = split_message(&text, 30);
+
+        assert_within_limit(&parts, 30);
+        let joined: String = parts.concat().split_whitespace().collect();
+        let original: String = text.split_whitespace().collect();
+        assert_eq!(joined, original);
+    }
+
+    #[test]
+    fn counts_utf16_units() {
+        // Every emoji takes two UTF-16 code units.
+        let text = "😀".repeat(30);
+        let parts = split_message(&text, 10);
+
+        assert_within_limit(&parts, 10);
+        assert_eq!(parts.len(), 6);
+        assert_eq!(parts.concat(), text);
+    }
+
+    #[test]
+    fn reopens_long_code_blocks() {
+        let lines: Vec<String> = (0..40)
+            .map(|index| format!("let x{} = {};", index, index))
+            .collect();
+        let text = format!("```rust\n{}\n```", lines.join("\n"));
+        let parts = split_message(&text, 100);
+
+        assert!(parts.len() > 1);
+        assert_within_limit(&parts, 100);
+        assert_fenced(&parts, "```rust", "```");
+        assert_eq!(code_body(&parts), lines);
+    }
+
+    #[test]
+    fn keeps_text_around_code_in_order() {
+        let code: Vec<String> = (0..10).map(|index| format!("line {}", index)).collect();
+        let text = format!(
+            "Before the code.\n\n```\n{}\n```\n\nAfter the code.",
+            code.join("\n")
+        );
+        let parts = split_message(&text, 40);
+
+        assert_within_limit(&parts, 40);
+        for part in &parts {
+            let fences = part.lines().filter(|line| *line == "```").count();
+            assert!(fences % 2 == 0, "unbalanced fences: {:?}", part);
+        }
+        let lines: Vec<&str> = parts
+            .iter()
+            .flat_map(|part| part.lines())
+            .filter(|line| !line.is_empty() && *line != "```")
+            .collect();
+        let expected: Vec<&str> = text
+            .lines()
+            .filter(|line| !line.is_empty() && *line != "```")
+            .collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn inner_shorter_fence_does_not_close_block() {
+        let lines = [
+            "# Example",
+            "```rust",
+            "fn main() {}",
+            "```",
+            "End of the file.",
+        ];
+        let text = format!("````md\n{}\n````", lines.join("\n"));
+        let parts = split_message(&text, 40);
+
+        assert!(parts.len() > 1);
+        assert_within_limit(&parts, 40);
+        assert_fenced(&parts, "````md", "````");
+        assert_eq!(code_body(&parts), lines);
+    }
+
+    #[test]
+    fn shortens_long_info_string() {
+        let lines: Vec<String> = (0..10).map(|index| format!("x = {}", index)).collect();
+        let opening = format!("```python title=\"{}\"", "long_name".repeat(5));
+        let text = format!("{}\n{}\n```", opening, lines.join("\n"));
+        let parts = split_message(&text, 40);
+
+        assert_within_limit(&parts, 40);
+        assert_fenced(&parts, "```python", "```");
+        assert_eq!(code_body(&parts), lines);
+    }
+
+    #[test]
+    fn backticks_do_not_close_tilde_fence() {
+        let lines = ["```", "code", "```", "more"];
+        let text = format!("~~~\n{}\n~~~", lines.join("\n"));
+        let parts = split_message(&text, 20);
+
+        assert_within_limit(&parts, 20);
+        assert_fenced(&parts, "~~~", "~~~");
+        assert_eq!(code_body(&parts), lines);
+    }
+
+    #[test]
+    fn splits_by_rendered_length() {
+        let render = |text: &str| text.replace('&', "&amp;");
+        let text = "Tom & Jerry. ".repeat(20);
+        let parts = split_rendered(text.trim(), 100, render);
+
+        assert!(parts.len() > split_message(text.trim(), 100).len());
+        for part in &parts {
+            assert!(
+                text_len(&render(part)) <= 100,
+                "part is too long: {:?}",
+                part
+            );
+        }
+        assert_eq!(parts.join(" "), text.trim());
+    }
+
+    #[test]
+    fn recognizes_fences() {
+        let backticks = Fence {
+            marker: '`',
+            len: 3,
+        };
+
+        assert_eq!(opening_fence("```rust"), Some(backticks));
+        assert_eq!(
+            opening_fence("  ~~~~"),
+            Some(Fence {
+                marker: '~',
+                len: 4
+            })
+        );
+        assert_eq!(opening_fence("``not a fence"), None);
+        assert_eq!(opening_fence("```a`b"), None);
+        assert!(is_closing_fence("````", backticks));
+        assert!(!is_closing_fence("```rust", backticks));
+        assert!(!is_closing_fence(
+            "```",
+            Fence {
+                marker: '`',
+                len: 4
+            }
+        ));
+    }
+}
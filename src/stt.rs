@@ -0,0 +1,155 @@
+use async_trait::async_trait;
+use reqwest::multipart::{Form, Part};
+use reqwest::RequestBuilder;
+use serde::Deserialize;
+use std::error::Error;
+
+const OPENAI_TRANSCRIPTIONS_URL: &str = "https://api.openai.com/v1/audio/transcriptions";
+
+#[derive(Deserialize)]
+struct Transcription {
+    text: String,
+}
+
+#[async_trait]
+pub trait SpeechToText: Send + Sync {
+    /// Transcribes an OGG/Opus voice note. `language` is an optional ISO-639-1 hint.
+    async fn transcribe(
+        &self,
+        audio: Vec<u8>,
+        language: Option<&str>,
+    ) -> Result<String, Box<dyn Error + Send + Sync>>;
+}
+
+/// OpenAI hosted Whisper API.
+pub struct OpenAiWhisper {
+    api_key: String,
+    model: String,
+}
+
+/// Self-hosted transcription server accepting the Whisper multipart shape at `STT_PATH`.
+pub struct HttpServer {
+    url: String,
+    api_key: Option<String>,
+}
+
+impl OpenAiWhisper {
+    pub fn new(api_key: &str, model: &str) -> Self {
+        OpenAiWhisper {
+            api_key: api_key.to_string(),
+            model: model.to_string(),
+        }
+    }
+}
+
+impl HttpServer {
+    pub fn new(url: &str, api_key: Option<String>) -> Self {
+        HttpServer {
+            url: url.to_string(),
+            api_key,
+        }
+    }
+}
+
+#[async_trait]
+impl SpeechToText for OpenAiWhisper {
+    async fn transcribe(
+        &self,
+        audio: Vec<u8>,
+        language: Option<&str>,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let form = audio_form(audio, language)?.text("model", self.model.clone());
+        let request = reqwest::Client::new()
+            .post(OPENAI_TRANSCRIPTIONS_URL)
+            .bearer_auth(&self.api_key)
+            .multipart(form);
+
+        send_transcription(request).await
+    }
+}
+
+#[async_trait]
+impl SpeechToText for HttpServer {
+    async fn transcribe(
+        &self,
+        audio: Vec<u8>,
+        language: Option<&str>,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let form = audio_form(audio, language)?;
+        let mut request = reqwest::Client::new().post(&self.url).multipart(form);
+
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        send_transcription(request).await
+    }
+}
+
+/// Builds the provider selected by `STT_PROVIDER` (`openai` or `local`).
+/// Without an explicit choice the local server is used when `STT_PATH` is set.
+pub fn provider() -> Result<Box<dyn SpeechToText>, Box<dyn Error + Send + Sync>> {
+    let stt_path = std::env::var("STT_PATH").unwrap_or_default();
+    let stt_key = std::env::var("STT_KEY").unwrap_or_default();
+    let default_provider = if stt_path.is_empty() {
+        "openai"
+    } else {
+        "local"
+    };
+    let provider_name =
+        std::env::var("STT_PROVIDER").unwrap_or_else(|_| default_provider.to_string());
+
+    match provider_name.as_str() {
+        "openai" => {
+            let api_key = if stt_key.is_empty() {
+                std::env::var("GPT_KEY").unwrap_or_default()
+            } else {
+                stt_key
+            };
+            let model = std::env::var("STT_MODEL").unwrap_or_else(|_| "whisper-1".to_string());
+
+            if api_key.is_empty() {
+                return Err("STT_KEY or GPT_KEY must be set for the openai STT provider".into());
+            }
+
+            Ok(Box::new(OpenAiWhisper::new(&api_key, &model)))
+        }
+        "local" => {
+            if stt_path.is_empty() {
+                return Err("STT_PATH must be set for the local STT provider".into());
+            }
+
+            let api_key = Some(stt_key).filter(|key| !key.is_empty());
+            Ok(Box::new(HttpServer::new(&stt_path, api_key)))
+        }
+        other => Err(format!("Unknown STT_PROVIDER: {}", other).into()),
+    }
+}
+
+fn audio_form(
+    audio: Vec<u8>,
+    language: Option<&str>,
+) -> Result<Form, Box<dyn Error + Send + Sync>> {
+    let audio_part = Part::bytes(audio)
+        .file_name("voice.ogg")
+        .mime_str("audio/ogg")?;
+    let mut form = Form::new().part("file", audio_part);
+
+    if let Some(language) = language {
+        form = form.text("language", language.to_string());
+    }
+
+    Ok(form)
+}
+
+async fn send_transcription(
+    request: RequestBuilder,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP stt error: {}", response.status()).into());
+    }
+
+    let transcription: Transcription = response.json().await?;
+    Ok(transcription.text.trim().to_string())
+}
@@ -0,0 +1,94 @@
+use chatgpt::types::ChatMessage;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::{cl100k_base_singleton, o200k_base_singleton, CoreBPE};
+
+/// Tokens left free in the context window for the model answer.
+const RESPONSE_RESERVE: usize = 1024;
+
+/// Every chat message is wrapped as `<|start|>{role}\n{content}<|end|>\n`.
+const TOKENS_PER_MESSAGE: usize = 4;
+
+/// Every reply is primed with `<|start|>assistant<|message|>`.
+const REPLY_PRIMING_TOKENS: usize = 3;
+
+/// Number of prompt tokens available for the prelude and the conversation history.
+pub fn history_budget(model: &str) -> usize {
+    context_size(model).saturating_sub(RESPONSE_RESERVE + REPLY_PRIMING_TOKENS)
+}
+
+/// Context windows of the models offered by `/model`. tiktoken-rs matches `gpt-4-turbo`
+/// by the `gpt-4` prefix and reports 8k instead of 128k, so these are listed here.
+fn context_size(model: &str) -> usize {
+    match model {
+        "gpt-4o" | "gpt-4o-mini" | "gpt-4-turbo" => 128_000,
+        "gpt-4" => 8_192,
+        "gpt-3.5-turbo" => 16_385,
+        _ => tiktoken_rs::model::get_context_size(model),
+    }
+}
+
+pub fn count_messages(model: &str, messages: &[ChatMessage]) -> usize {
+    with_tokenizer(model, |bpe| {
+        messages
+            .iter()
+            .map(|message| message_tokens(bpe, message))
+            .sum()
+    })
+}
+
+/// Keeps the newest messages that fit into `budget` tokens, dropping the oldest turns first.
+/// The latest message is always kept so the current request is never lost.
+pub fn trim_history(model: &str, history: Vec<ChatMessage>, budget: usize) -> Vec<ChatMessage> {
+    let keep_from = with_tokenizer(model, |bpe| {
+        let mut used = 0;
+        let mut keep_from = history.len();
+
+        for (index, message) in history.iter().enumerate().rev() {
+            let tokens = message_tokens(bpe, message);
+            if used + tokens > budget && keep_from < history.len() {
+                break;
+            }
+
+            used += tokens;
+            keep_from = index;
+        }
+
+        keep_from
+    });
+
+    history.into_iter().skip(keep_from).collect()
+}
+
+/// Counts the oldest messages that fit into `budget` tokens, at least one so
+/// that a caller walking through the history always moves forward.
+pub fn fit_oldest(model: &str, history: &[ChatMessage], budget: usize) -> usize {
+    with_tokenizer(model, |bpe| {
+        let mut used = 0;
+        let mut count = 0;
+
+        for message in history {
+            let tokens = message_tokens(bpe, message);
+            if used + tokens > budget && count > 0 {
+                break;
+            }
+
+            used += tokens;
+            count += 1;
+        }
+
+        count
+    })
+}
+
+fn message_tokens(bpe: &CoreBPE, message: &ChatMessage) -> usize {
+    TOKENS_PER_MESSAGE + bpe.encode_with_special_tokens(&message.content).len()
+}
+
+fn with_tokenizer<T>(model: &str, job: impl FnOnce(&CoreBPE) -> T) -> T {
+    let bpe = match get_tokenizer(model) {
+        Some(Tokenizer::O200kBase) => o200k_base_singleton(),
+        _ => cl100k_base_singleton(),
+    };
+    let bpe = bpe.lock();
+    job(&bpe)
+}
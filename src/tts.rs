@@ -0,0 +1,206 @@
+use crate::split::split_message;
+use serde::Deserialize;
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+use tokio::process::Command;
+
+const DEFAULT_CHUNK_SIZE: usize = 800;
+const DEFAULT_PARALLELISM: usize = 3;
+const DEFAULT_MERGE_MAX_SECONDS: usize = 300;
+/// Telegram shows larger OGG files as documents instead of voice messages.
+const DEFAULT_MERGE_MAX_BYTES: usize = 1024 * 1024;
+/// Sample rate of the Opus granule positions.
+const OPUS_SAMPLE_RATE: u64 = 48_000;
+
+pub const MIN_RATE: f32 = 0.5;
+pub const MAX_RATE: f32 = 2.0;
+
+/// Voice parameters sent to the TTS server along with the text, unset ones are
+/// left to the server defaults.
+#[derive(Clone, Copy)]
+pub struct VoiceSettings<'a> {
+    pub speaker: Option<&'a str>,
+    pub rate: Option<f32>,
+    pub language: Option<&'a str>,
+}
+
+/// Voice advertised by the TTS server at `TTS_VOICES_PATH`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Voice {
+    pub id: String,
+    pub name: Option<String>,
+    pub language: Option<String>,
+}
+
+impl Voice {
+    pub fn title(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.id)
+    }
+}
+
+/// The voice list may come as a bare array or wrapped into `{"voices": [...]}`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum VoiceList {
+    List(Vec<Voice>),
+    Wrapped { voices: Vec<Voice> },
+}
+
+fn env_usize(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(default)
+}
+
+/// How many chunks are synthesized at the same time, `TTS_PARALLELISM`.
+pub fn parallelism() -> usize {
+    env_usize("TTS_PARALLELISM", DEFAULT_PARALLELISM)
+}
+
+/// Splits the text into chunks of at most `TTS_CHUNK_SIZE` characters, breaking
+/// between paragraphs and sentences and only cutting a sentence that is too long.
+pub fn chunks(text: &str) -> Vec<String> {
+    split_message(text, env_usize("TTS_CHUNK_SIZE", DEFAULT_CHUNK_SIZE))
+        .into_iter()
+        .filter(|chunk| !chunk.trim().is_empty())
+        .collect()
+}
+
+pub fn is_valid_rate(rate: f32) -> bool {
+    (MIN_RATE..=MAX_RATE).contains(&rate)
+}
+
+/// Voices the TTS server offers, empty when `TTS_VOICES_PATH` is not set.
+pub async fn voices() -> Result<Vec<Voice>, Box<dyn Error + Send + Sync>> {
+    let voices_path = std::env::var("TTS_VOICES_PATH").unwrap_or_default();
+    if voices_path.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let response = reqwest::get(voices_path).await?.error_for_status()?;
+    let voices = match response.json::<VoiceList>().await? {
+        VoiceList::List(voices) | VoiceList::Wrapped { voices } => voices,
+    };
+
+    Ok(voices)
+}
+
+/// Requests a voice message for the text from the server at `TTS_PATH`.
+pub async fn synthesize(
+    text: &str,
+    voice: &VoiceSettings<'_>,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut json_body = serde_json::json!({ "text": text });
+    if let Some(speaker) = voice.speaker {
+        json_body["speaker"] = serde_json::json!(speaker);
+    }
+    if let Some(rate) = voice.rate {
+        json_body["rate"] = serde_json::json!(rate);
+    }
+    if let Some(language) = voice.language {
+        json_body["language"] = serde_json::json!(language);
+    }
+
+    let response = reqwest::Client::new()
+        .post(std::env::var("TTS_PATH").unwrap_or_default())
+        .json(&json_body)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(format!("HTTP tts error: {}", response.status()).into());
+    }
+
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Whether the parts of an answer are sent as one voice message, `TTS_MERGE`.
+pub fn merge_enabled() -> bool {
+    std::env::var("TTS_MERGE")
+        .map(|value| matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+/// Concatenates the voice messages into one OGG/Opus file with ffmpeg. Returns `None`
+/// when the result is longer than `TTS_MERGE_MAX_SECONDS` or larger than
+/// `TTS_MERGE_MAX_BYTES`, the parts should then be sent separately.
+pub async fn merge(parts: &[Vec<u8>]) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+    let dir = std::env::temp_dir().join(format!("tts-{}", uuid::Uuid::new_v4().simple()));
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let result = merge_in(&dir, parts).await;
+    if let Err(err) = tokio::fs::remove_dir_all(&dir).await {
+        log::warn!("Failed to remove {}: {}", dir.display(), err);
+    }
+    let merged = result?;
+
+    let max_duration =
+        Duration::from_secs(env_usize("TTS_MERGE_MAX_SECONDS", DEFAULT_MERGE_MAX_SECONDS) as u64);
+    let is_too_long = ogg_opus_duration(&merged).is_none_or(|duration| duration > max_duration);
+    let is_too_large = merged.len() > env_usize("TTS_MERGE_MAX_BYTES", DEFAULT_MERGE_MAX_BYTES);
+    if is_too_long || is_too_large {
+        log::info!(
+            "Merged voice message of {} bytes is over the limits, sending {} parts",
+            merged.len(),
+            parts.len()
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(merged))
+}
+
+async fn merge_in(dir: &Path, parts: &[Vec<u8>]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut command = Command::new("ffmpeg");
+    command.args(["-hide_banner", "-loglevel", "error", "-y"]);
+
+    for (index, part) in parts.iter().enumerate() {
+        let path = dir.join(format!("part_{}", index));
+        tokio::fs::write(&path, part).await?;
+        command.arg("-i").arg(path);
+    }
+
+    let inputs: String = (0..parts.len())
+        .map(|index| format!("[{}:a]", index))
+        .collect();
+    let output = dir.join("merged.ogg");
+    command
+        .arg("-filter_complex")
+        .arg(format!("{}concat=n={}:v=0:a=1", inputs, parts.len()))
+        .args(["-c:a", "libopus", "-b:a", "32k", "-application", "voip"])
+        .arg(&output);
+
+    let result = command.output().await?;
+    if !result.status.success() {
+        return Err(format!(
+            "ffmpeg failed: {}",
+            String::from_utf8_lossy(&result.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(tokio::fs::read(&output).await?)
+}
+
+/// Duration of an OGG/Opus stream, read from the granule position of its last page.
+fn ogg_opus_duration(data: &[u8]) -> Option<Duration> {
+    let find_last = |pattern: &[u8]| {
+        data.windows(pattern.len())
+            .rposition(|window| window == pattern)
+    };
+    let find_first = |pattern: &[u8]| {
+        data.windows(pattern.len())
+            .position(|window| window == pattern)
+    };
+
+    let head = find_first(b"OpusHead")?;
+    let pre_skip = u16::from_le_bytes(data.get(head + 10..head + 12)?.try_into().ok()?);
+    let page = find_last(b"OggS")?;
+    let granule = u64::from_le_bytes(data.get(page + 6..page + 14)?.try_into().ok()?);
+
+    let samples = granule.checked_sub(pre_skip as u64)?;
+    Some(Duration::from_millis(samples * 1000 / OPUS_SAMPLE_RATE))
+}
@@ -1,116 +1,385 @@
 use crate::{
-    db::{User, DB},
-    gpt::MyGPT,
+    db::{Conversation, DbError, Group, Persona, User, UserRole, DB},
+    gpt::{user_persona_name, MyGPT},
+    markdown::{extract_code_blocks, segments, to_telegram_html, CodeAttachment, Segment},
+    speech::{self, Language},
+    split::{split_message, split_rendered, TELEGRAM_MESSAGE_LIMIT},
+    stt,
+    tts::{self, VoiceSettings},
 };
 use chatgpt::types::Role;
-use lazy_static::lazy_static;
+use futures::StreamExt;
 use log::info;
-use reqwest;
-use std::{env, error::Error, fs, sync::Mutex};
+use std::{
+    error::Error,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use teloxide::{
     net::Download,
     prelude::*,
     types::InputFile,
-    types::{ChatAction, FileMeta},
+    types::ParseMode,
+    types::{ChatAction, FileMeta, MessageEntityKind, MessageId, MessageKind},
+    ApiError, RequestError,
 };
-use tokio::fs::OpenOptions;
 use tokio_interval::{clear_timer, set_interval};
-use uuid::Uuid;
+
+const DEFAULT_STREAM_EDIT_INTERVAL_MS: u64 = 1500;
+const DEFAULT_CODE_ATTACHMENT_MIN_CHARS: usize = 1500;
+const DEFAULT_CONTACT_FORM: &str = "ты";
 
 #[derive(Debug)]
 pub struct State {
     pub users: Mutex<Vec<User>>,
+    pub groups: Mutex<Vec<Group>>,
+    /// Username of the bot itself, used to recognize mentions and commands in groups.
+    pub bot_username: String,
 }
 
 pub struct TextMessage<'a> {
+    pub db: &'a DB,
     pub user: &'a User,
     pub bot: Bot,
     pub chat_id: ChatId,
+    pub conversation: Conversation,
     pub message: &'a str,
 }
 
-lazy_static! {
-    static ref DATABASE: DB = DB::new();
+/// Finds the user by Telegram id. Users added by username are matched by it only
+/// until their id is bound, so a freed username can't be used to take over access.
+pub fn find_user<'a>(users: &'a [User], from: &teloxide::types::User) -> Option<&'a User> {
+    let telegram_id = from.id.0 as i64;
+
+    if let Some(user) = users
+        .iter()
+        .find(|user| user.telegram_id == Some(telegram_id))
+    {
+        return Some(user);
+    }
+
+    let username = from.username.as_deref()?;
+    users
+        .iter()
+        .find(|user| user.telegram_id.is_none() && user.user_name.eq_ignore_ascii_case(username))
+}
+
+/// Returns the sender of the message if authorized, binding their Telegram id and
+/// refreshing the stored display username on the way.
+pub async fn authorize_user(
+    users: &[User],
+    msg: &Message,
+    db: &DB,
+    state: &Arc<Mutex<State>>,
+) -> Option<User> {
+    let from = msg.from()?;
+    let user = find_user(users, from)?.clone();
+
+    let is_bound = user.telegram_id.is_some();
+    let is_renamed =
+        matches!(&from.username, Some(username) if !username.eq_ignore_ascii_case(&user.user_name));
+    if is_bound && !is_renamed {
+        return Some(user);
+    }
+
+    let result = db
+        .bind_telegram_user(user.id, from.id.0 as i64, from.username.as_deref())
+        .await;
+    let result = match result {
+        Ok(_) => refresh_users(db, state).await,
+        Err(err) => Err(err),
+    };
+
+    if let Err(err) = result {
+        log::error!("Failed to bind Telegram user: {}", err);
+        sentry::capture_error(&err);
+    }
+
+    Some(user)
+}
+
+/// Members of an authorized group who are not in the users table talk to the bot as read-only guests.
+pub async fn authorize_group_member(
+    users: &[User],
+    msg: &Message,
+    db: &DB,
+    state: &Arc<Mutex<State>>,
+) -> Option<User> {
+    if let Some(user) = authorize_user(users, msg, db, state).await {
+        return Some(user);
+    }
+
+    find_group(state, msg.chat.id)?;
+    msg.from().map(guest_user)
 }
 
-pub fn find_user_by_username<'a>(users: &'a Vec<User>, username: &'a str) -> Option<&'a User> {
-    users.iter().find(|user| user.user_name == username)
+fn guest_user(from: &teloxide::types::User) -> User {
+    User {
+        id: 0,
+        telegram_id: Some(from.id.0 as i64),
+        user_name: from
+            .username
+            .clone()
+            .unwrap_or_else(|| from.id.0.to_string()),
+        chat_id: None,
+        contact_name: from.first_name.clone(),
+        contact_form: default_contact_form(),
+        is_voice: false,
+        language: None,
+        is_echo: false,
+        is_transcribe_only: false,
+        model: None,
+        temperature: None,
+        persona: None,
+        role: UserRole::Readonly,
+        tts_voice: None,
+        tts_rate: None,
+        tts_language: None,
+    }
+}
+
+pub fn default_contact_form() -> String {
+    std::env::var("DEFAULT_CONTACT_FORM").unwrap_or_else(|_| DEFAULT_CONTACT_FORM.to_string())
+}
+
+pub fn find_group(state: &Arc<Mutex<State>>, chat_id: ChatId) -> Option<Group> {
+    let state = state.lock().unwrap();
+    let groups = state.groups.lock().unwrap();
+    groups
+        .iter()
+        .find(|group| group.chat_id == chat_id)
+        .cloned()
+}
+
+/// History of the message chat or forum topic, or of its sender when the group keeps per-member histories.
+pub fn conversation_for(msg: &Message, group: Option<&Group>) -> Conversation {
+    let conversation = match (group, msg.from()) {
+        (Some(group), Some(from)) if !group.shared_history => {
+            Conversation::member(msg.chat.id, from.id.0 as i64)
+        }
+        _ => Conversation::chat(msg.chat.id),
+    };
+
+    conversation.in_thread(topic_id(msg))
+}
+
+/// Forum topic of the message. Replies in regular groups carry a thread id as well,
+/// those are not topics and share the chat history.
+pub fn topic_id(msg: &Message) -> Option<i32> {
+    match &msg.kind {
+        MessageKind::Common(common) if common.is_topic_message => msg.thread_id,
+        _ => None,
+    }
+}
+
+pub async fn refresh_users(db: &DB, state: &Arc<Mutex<State>>) -> Result<(), DbError> {
+    let users_list = db.get_users().await?;
+    state.lock().unwrap().users = Mutex::new(users_list);
+    Ok(())
+}
+
+pub async fn refresh_groups(db: &DB, state: &Arc<Mutex<State>>) -> Result<(), DbError> {
+    let groups = db.get_groups().await?;
+    state.lock().unwrap().groups = Mutex::new(groups);
+    Ok(())
 }
 
 pub async fn send_message(bot: Bot, chat_id: ChatId, message: &str) {
-    let result = bot.send_message(chat_id, message).await;
+    send_thread_message(bot, chat_id, None, message).await;
+}
 
-    match result {
-        Ok(_) => {}
-        Err(err) => {
+/// Sends the message into the forum topic `thread_id`, or to the chat itself when it is `None`.
+pub async fn send_thread_message(bot: Bot, chat_id: ChatId, thread_id: Option<i32>, message: &str) {
+    for part in split_formatted(message) {
+        if let Err(err) = send_formatted(&bot, chat_id, thread_id, &part).await {
             sentry::capture_error(&err);
+            return;
         }
     }
 }
 
-pub async fn send_tts(
-    bot: Bot,
+/// Splits a markdown message so that every part still fits into a Telegram message
+/// once rendered, list indents and table separators make the text longer. The HTML
+/// is measured with its tags, so it is never shorter than what Telegram counts.
+fn split_formatted(message: &str) -> Vec<String> {
+    split_rendered(message, TELEGRAM_MESSAGE_LIMIT, to_telegram_html)
+}
+
+/// Sends a markdown message rendered as Telegram HTML, or as plain text when
+/// Telegram rejects the rendered markup.
+async fn send_formatted(
+    bot: &Bot,
     chat_id: ChatId,
+    thread_id: Option<i32>,
     message: &str,
-) -> Result<bool, Box<dyn Error + Send + Sync>> {
-    let json_body = serde_json::json!({ "text": message });
-
-    let client = reqwest::Client::new();
-    let response = client
-        .post(std::env::var("TTS_PATH").unwrap_or_default())
-        .json(&json_body)
-        .send()
-        .await;
+) -> Result<Message, RequestError> {
+    let mut request = bot
+        .send_message(chat_id, to_telegram_html(message))
+        .parse_mode(ParseMode::Html);
+    if let Some(thread_id) = thread_id {
+        request = request.message_thread_id(thread_id);
+    }
 
-    match response {
-        Ok(resp) => {
-            if !resp.status().is_success() {
-                return Err("HTTP tts error".into());
+    match request.await {
+        Err(err) if is_formatting_error(&err) => {
+            log::warn!("Sending as plain text, formatting rejected: {}", err);
+            let mut request = bot.send_message(chat_id, message);
+            if let Some(thread_id) = thread_id {
+                request = request.message_thread_id(thread_id);
             }
+            request.await
+        }
+        result => result,
+    }
+}
 
-            let body = resp.bytes().await;
-            let audio_stream = InputFile::memory(body.unwrap());
-            let response = bot.send_voice(chat_id, audio_stream).await;
+fn is_formatting_error(error: &RequestError) -> bool {
+    match error {
+        RequestError::Api(ApiError::CantParseEntities) => true,
+        // The rendered markup may still be over the limit when the markdown is not.
+        RequestError::Api(ApiError::MessageIsTooLong | ApiError::EditedMessageIsTooLong) => true,
+        RequestError::Api(ApiError::Unknown(message)) => message.contains("can't parse entities"),
+        _ => false,
+    }
+}
 
-            match response {
-                Ok(_) => Ok(true),
-                Err(error) => Err(error.into()),
-            }
-        }
-        Err(error) => {
-            sentry::capture_error(&error);
-            return Err(error.into());
+pub async fn report_db_error(bot: Bot, chat_id: ChatId, thread_id: Option<i32>, error: &DbError) {
+    log::error!("Database error: {}", error);
+    sentry::capture_error(error);
+    send_thread_message(
+        bot,
+        chat_id,
+        thread_id,
+        "Something went wrong with my memory, please try again later",
+    )
+    .await;
+}
+
+pub async fn send_reply(bot: Bot, chat_id: ChatId, reply_to: MessageId, message: &str) {
+    for part in split_message(message, TELEGRAM_MESSAGE_LIMIT) {
+        let result = bot
+            .send_message(chat_id, part)
+            .reply_to_message_id(reply_to)
+            .await;
+
+        if let Err(err) = result {
+            sentry::capture_error(&err);
+            return;
         }
     }
 }
 
-pub async fn send_tts_multi_parts(bot: Bot, chat_id: ChatId, message: &str) {
-    let parts = textwrap::wrap(message, 800);
+pub async fn send_voice(
+    bot: &Bot,
+    chat_id: ChatId,
+    thread_id: Option<i32>,
+    audio: Vec<u8>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut request = bot.send_voice(chat_id, InputFile::memory(audio));
+    if let Some(thread_id) = thread_id {
+        request = request.message_thread_id(thread_id);
+    }
+
+    request.await?;
+    Ok(())
+}
 
-    for (_, part) in parts.iter().enumerate() {
-        let cloned_bot = bot.clone();
-        let tts_success = send_tts(cloned_bot, chat_id, &part.to_string()).await;
-        match tts_success {
-            Ok(_) => {}
+/// Speaks the message in parts, numbers and dates are spelled out in the voice
+/// language. Up to `TTS_PARALLELISM` parts are synthesized at once, voice
+/// messages are still sent in order. Parts the TTS server fails on are sent as text.
+/// With `TTS_MERGE` the parts are joined into one voice message when they all succeed.
+pub async fn send_tts_multi_parts(
+    bot: Bot,
+    chat_id: ChatId,
+    thread_id: Option<i32>,
+    message: &str,
+    voice: &VoiceSettings<'_>,
+) {
+    let language = Language::detect(voice.language, message);
+    // Spelled out numbers and dates are much longer than the digits, so the text
+    // is chunked after normalizing to keep every request within `TTS_CHUNK_SIZE`.
+    let text = speech::normalize(message, language);
+    let parts = tts::chunks(&text);
+    let is_merged = tts::merge_enabled() && parts.len() > 1;
+
+    let mut voices = futures::stream::iter(parts)
+        .map(|part| async move {
+            let audio = tts::synthesize(&part, voice).await;
+            (part, audio)
+        })
+        .buffered(tts::parallelism());
+
+    if !is_merged {
+        while let Some((part, audio)) = voices.next().await {
+            send_tts_part(&bot, chat_id, thread_id, &part, audio).await;
+        }
+        return;
+    }
+
+    let voices: Vec<_> = voices.collect().await;
+    let audios: Option<Vec<Vec<u8>>> = voices
+        .iter()
+        .map(|(_, audio)| audio.as_ref().ok().cloned())
+        .collect();
+
+    if let Some(audios) = audios {
+        let result = match tts::merge(&audios).await {
+            Ok(Some(merged)) => send_voice(&bot, chat_id, thread_id, merged)
+                .await
+                .map(|_| true),
+            Ok(None) => Ok(false),
+            Err(error) => Err(error),
+        };
+
+        match result {
+            Ok(true) => return,
+            Ok(false) => {}
             Err(error) => {
+                log::warn!("Failed to send merged voice message: {}", error);
                 sentry::capture_error(&*error);
-                send_message(bot.clone(), chat_id, &part).await;
             }
         }
     }
+
+    for (part, audio) in voices {
+        send_tts_part(&bot, chat_id, thread_id, &part, audio).await;
+    }
 }
 
-pub async fn send_typing_action(bot: Bot, chat_id: ChatId) {
-    match bot.send_chat_action(chat_id, ChatAction::Typing).await {
-        Ok(_) => {}
-        Err(err) => {
-            sentry::capture_error(&err);
-        }
+async fn send_tts_part(
+    bot: &Bot,
+    chat_id: ChatId,
+    thread_id: Option<i32>,
+    part: &str,
+    audio: Result<Vec<u8>, Box<dyn Error + Send + Sync>>,
+) {
+    let result = match audio {
+        Ok(audio) => send_voice(bot, chat_id, thread_id, audio).await,
+        Err(error) => Err(error),
     };
+
+    if let Err(error) = result {
+        sentry::capture_error(&*error);
+        send_thread_message(bot.clone(), chat_id, thread_id, part).await;
+    }
+}
+
+pub async fn send_typing_action(bot: Bot, chat_id: ChatId, thread_id: Option<i32>) {
+    send_chat_action(bot, chat_id, thread_id, ChatAction::Typing).await;
+}
+
+pub async fn send_voice_recording_action(bot: Bot, chat_id: ChatId, thread_id: Option<i32>) {
+    send_chat_action(bot, chat_id, thread_id, ChatAction::RecordVoice).await;
 }
 
-pub async fn send_voice_recording_action(bot: Bot, chat_id: ChatId) {
-    match bot.send_chat_action(chat_id, ChatAction::RecordVoice).await {
+async fn send_chat_action(bot: Bot, chat_id: ChatId, thread_id: Option<i32>, action: ChatAction) {
+    let mut request = bot.send_chat_action(chat_id, action);
+    if let Some(thread_id) = thread_id {
+        request = request.message_thread_id(thread_id);
+    }
+
+    match request.await {
         Ok(_) => {}
         Err(err) => {
             sentry::capture_error(&err);
@@ -122,176 +391,476 @@ pub fn is_command_message(msg: Message) -> bool {
     let message = msg.text();
 
     match message {
-        Some(text) => {
-            let first_char = text.chars().nth(0).unwrap();
-            if first_char == '/' {
-                return true;
-            }
-            return false;
-        }
-        None => return false,
+        Some(text) => text.starts_with('/'),
+        None => false,
     }
 }
 
 pub async fn proccess_text_message(args: TextMessage<'_>) {
     let gpt_api_key = std::env::var("GPT_KEY").expect("GPT_KEY must be set.");
-    let gpt = MyGPT::new(&gpt_api_key);
+    let gpt = MyGPT::new(&gpt_api_key, args.user);
     let cloned_user = args.user.clone();
 
-    let result = gpt.send_msg(args.chat_id, args.user, &args.message).await;
-
     log::info!("[{}]: {}", cloned_user.user_name, args.message);
 
+    if !is_tts_enabled(&cloned_user) {
+        proccess_streaming_message(&gpt, args).await;
+        return;
+    }
+
+    let thread_id = args.conversation.thread_id;
+    let result = gpt
+        .send_msg(args.db, args.conversation, args.user, args.message)
+        .await;
+
     match result {
         Ok(content) => {
             log::info!("[bot]: {}", content);
-            let is_voice_response =
-                is_tts_enabled(&cloned_user) && !is_code_listing(content.as_str());
-
-            DATABASE.save_message(args.chat_id, Role::Assistant, &content);
+            save_assistant_message(args.db, args.conversation, &content).await;
 
-            if !is_voice_response {
-                send_message(args.bot, args.chat_id, &content).await;
-                return;
+            let persona = match args.db.get_persona(&user_persona_name(args.user)).await {
+                Ok(persona) => persona,
+                Err(err) => {
+                    sentry::capture_error(&err);
+                    None
+                }
+            };
+            let voice = voice_settings(args.user, persona.as_ref());
+
+            // Prose is spoken, code can't be listened to and is sent as text in between.
+            for segment in segments(&content) {
+                match segment {
+                    Segment::Prose(prose) => {
+                        send_tts_multi_parts(
+                            args.bot.clone(),
+                            args.chat_id,
+                            thread_id,
+                            &prose,
+                            &voice,
+                        )
+                        .await;
+                    }
+                    Segment::Code(code) => {
+                        let (text, attachments) = extract_code_attachments(&code);
+                        if !text.trim().is_empty() {
+                            send_thread_message(args.bot.clone(), args.chat_id, thread_id, &text)
+                                .await;
+                        }
+                        send_code_attachments(&args.bot, args.chat_id, thread_id, attachments)
+                            .await;
+                    }
+                }
             }
-
-            send_tts_multi_parts(args.bot.clone(), args.chat_id, &content).await;
         }
+        Err(error) => report_gpt_error(args.bot, args.chat_id, thread_id, &*error).await,
+    }
+}
+
+/// Sends the first streamed piece as a message and keeps editing it while the answer
+/// grows, at most once per `STREAM_EDIT_INTERVAL_MS` to stay within Telegram rate limits.
+async fn proccess_streaming_message(gpt: &MyGPT, args: TextMessage<'_>) {
+    let stream = gpt
+        .send_msg_streaming(args.db, args.conversation, args.user, args.message)
+        .await;
+    let thread_id = args.conversation.thread_id;
+
+    let mut stream = match stream {
+        Ok(stream) => stream,
         Err(error) => {
-            info!("Error: {}", error);
-            send_message(args.bot, args.chat_id, "I broke down. I feel bad").await;
+            report_gpt_error(args.bot, args.chat_id, thread_id, &*error).await;
+            return;
+        }
+    };
+
+    let edit_interval = Duration::from_millis(
+        std::env::var("STREAM_EDIT_INTERVAL_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_STREAM_EDIT_INTERVAL_MS),
+    );
+
+    let mut content = String::new();
+    let mut sent_parts: Vec<(MessageId, String)> = Vec::new();
+    let mut last_update: Option<Instant> = None;
+
+    while let Some(delta) = stream.deltas.recv().await {
+        content.push_str(&delta);
 
-            let error_ref: &dyn Error = &*error;
-            sentry::capture_error(error_ref);
+        if content.trim().is_empty() {
+            continue;
+        }
+
+        if last_update.is_none_or(|last_update| last_update.elapsed() >= edit_interval) {
+            sync_streamed_parts(
+                &args.bot,
+                args.chat_id,
+                thread_id,
+                &content,
+                &mut sent_parts,
+            )
+            .await;
+            last_update = Some(Instant::now());
         }
     }
-}
 
-pub fn is_tts_enabled(user: &User) -> bool {
-    let tts_path = std::env::var("TTS_PATH").unwrap_or_default();
-    if tts_path.is_empty() || !user.is_voice {
-        return false;
+    let completion = match stream.completion.await {
+        Ok(Ok(_)) if content.trim().is_empty() => Err("Empty GPT response".into()),
+        Ok(result) => result,
+        Err(err) => Err(err.into()),
+    };
+
+    // Big code blocks are only moved to files once the answer is complete.
+    let (text, attachments) = extract_code_attachments(&content);
+    if !text.trim().is_empty() {
+        sync_streamed_parts(&args.bot, args.chat_id, thread_id, &text, &mut sent_parts).await;
     }
+    send_code_attachments(&args.bot, args.chat_id, thread_id, attachments).await;
 
-    return true;
+    match completion {
+        Ok(_) => {
+            log::info!("[bot]: {}", content);
+            save_assistant_message(args.db, args.conversation, &content).await;
+        }
+        Err(error) => report_gpt_error(args.bot, args.chat_id, thread_id, &*error).await,
+    }
 }
 
-pub async fn asr(bot: Bot, file: &FileMeta) -> &'static str {
-    let dir = env::temp_dir();
-    let tmp_file_path = format!("{}{}.ogg", dir.display(), Uuid::new_v4());
-
-    log::info!("Voice: {:?}", file.id);
-    log::info!("Temporary directory: {}", tmp_file_path);
+/// Brings the sent messages in line with the answer so far: parts that changed
+/// are edited and the ones over the Telegram length limit are sent as new messages.
+async fn sync_streamed_parts(
+    bot: &Bot,
+    chat_id: ChatId,
+    thread_id: Option<i32>,
+    content: &str,
+    sent_parts: &mut Vec<(MessageId, String)>,
+) {
+    let parts = split_formatted(content);
+    let parts_count = parts.len();
+
+    for (index, part) in parts.into_iter().enumerate() {
+        if let Some((message_id, sent_part)) = sent_parts.get_mut(index) {
+            if *sent_part != part {
+                edit_message(bot, chat_id, *message_id, &part).await;
+                *sent_part = part;
+            }
+            continue;
+        }
 
-    let mut local_file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .append(true)
-        .open(&tmp_file_path)
-        .await
-        .unwrap();
+        match send_formatted(bot, chat_id, thread_id, &part).await {
+            Ok(message) => sent_parts.push((message.id, part)),
+            Err(err) => {
+                sentry::capture_error(&err);
+                break;
+            }
+        }
+    }
 
-    let file_response = bot.get_file(&file.id).await;
-    match file_response {
-        Ok(file_request) => {
-            log::info!("FILE: {:?}", file_request.path);
-            bot.download_file(&file_request.path, &mut local_file)
-                .await
-                .unwrap();
+    // The content may get shorter once code blocks are moved to attachments.
+    let drop_from = parts_count.min(sent_parts.len());
+    for (message_id, _) in sent_parts.drain(drop_from..) {
+        if let Err(err) = bot.delete_message(chat_id, message_id).await {
+            log::warn!("Failed to delete streamed message: {}", err);
+        }
+    }
+}
 
-            // TODO: proccess data to STT API
+/// Takes fenced code blocks longer than `CODE_ATTACHMENT_MIN_CHARS` out of the answer.
+fn extract_code_attachments(content: &str) -> (String, Vec<CodeAttachment>) {
+    let min_chars = std::env::var("CODE_ATTACHMENT_MIN_CHARS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_CODE_ATTACHMENT_MIN_CHARS);
 
-            fs::remove_file(tmp_file_path).unwrap();
+    extract_code_blocks(content, min_chars)
+}
 
-            "test from asr goes here"
+async fn send_code_attachments(
+    bot: &Bot,
+    chat_id: ChatId,
+    thread_id: Option<i32>,
+    attachments: Vec<CodeAttachment>,
+) {
+    for attachment in attachments {
+        let file = InputFile::memory(attachment.code.into_bytes()).file_name(attachment.file_name);
+        let mut request = bot.send_document(chat_id, file);
+        if let Some(thread_id) = thread_id {
+            request = request.message_thread_id(thread_id);
         }
-        Err(err) => {
-            log::error!("Failed to create file request: {:?}", err);
-            ""
+
+        if let Err(err) = request.await {
+            sentry::capture_error(&err);
         }
     }
 }
 
-pub fn is_code_listing(text: &str) -> bool {
-    let indented_lines = text.lines().filter(|line| line.starts_with("    ")).count();
-    let total_lines = text.lines().count();
-    let indentation_ratio = indented_lines as f32 / total_lines as f32;
+async fn edit_message(bot: &Bot, chat_id: ChatId, message_id: MessageId, message: &str) {
+    let result = bot
+        .edit_message_text(chat_id, message_id, to_telegram_html(message))
+        .parse_mode(ParseMode::Html)
+        .await;
+
+    let result = match result {
+        Err(err) if is_formatting_error(&err) => {
+            bot.edit_message_text(chat_id, message_id, message).await
+        }
+        result => result,
+    };
 
-    if indentation_ratio > 0.5 {
-        return true;
+    if let Err(err) = result {
+        log::warn!("Failed to edit streamed message: {}", err);
     }
+}
 
-    let keywords = ["fn"];
-    if keywords.iter().any(|&keyword| text.contains(keyword)) {
-        return true;
+async fn save_assistant_message(db: &DB, conversation: Conversation, content: &str) {
+    if let Err(err) = db
+        .save_message(conversation, Role::Assistant, content)
+        .await
+    {
+        log::error!("Failed to save assistant message: {}", err);
+        sentry::capture_error(&err);
     }
+}
+
+async fn report_gpt_error(
+    bot: Bot,
+    chat_id: ChatId,
+    thread_id: Option<i32>,
+    error: &(dyn Error + Send + Sync),
+) {
+    info!("Error: {}", error);
+    send_thread_message(bot, chat_id, thread_id, "I broke down. I feel bad").await;
+    sentry::capture_error(error);
+}
 
-    let syntax_characters = ['{', '}', '(', ')', '`'];
-    if syntax_characters.iter().any(|&c| text.contains(c)) {
-        return true;
+/// The voice chosen with /voicecfg wins over the persona one, the language of the
+/// chosen voice over the user and persona languages.
+fn voice_settings<'a>(user: &'a User, persona: Option<&'a Persona>) -> VoiceSettings<'a> {
+    VoiceSettings {
+        speaker: user
+            .tts_voice
+            .as_deref()
+            .or(persona.and_then(|persona| persona.voice.as_deref())),
+        rate: user.tts_rate,
+        language: user
+            .tts_language
+            .as_deref()
+            .or(user.language.as_deref())
+            .or(persona.and_then(|persona| persona.language.as_deref())),
     }
+}
 
-    false
+pub fn is_tts_enabled(user: &User) -> bool {
+    let tts_path = std::env::var("TTS_PATH").unwrap_or_default();
+    !tts_path.is_empty() && user.is_voice
 }
 
-pub async fn proccess_message(user: &User, bot: Bot, msg: &Message) {
-    let voice = msg.voice();
-    let message = msg.text();
+pub async fn asr(
+    bot: Bot,
+    file: &FileMeta,
+    language: Option<&str>,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    log::info!("Voice: {:?}", file.id);
 
-    let mut content = "";
+    let provider = stt::provider()?;
+    let file_request = bot.get_file(&file.id).await?;
+    log::info!("FILE: {:?}", file_request.path);
 
-    if voice.is_some() {
-        content = asr(bot.clone(), &voice.unwrap().file).await;
-    }
+    let mut audio: Vec<u8> = Vec::new();
+    bot.download_file(&file_request.path, &mut audio).await?;
+
+    provider.transcribe(audio, language).await
+}
+
+/// Byte ranges of the bot mentions in the message text.
+fn bot_mentions(msg: &Message, bot_username: &str) -> Vec<Range<usize>> {
+    msg.parse_entities()
+        .unwrap_or_default()
+        .iter()
+        .filter(|entity| matches!(entity.kind(), MessageEntityKind::Mention))
+        .filter(|entity| entity.text()[1..].eq_ignore_ascii_case(bot_username))
+        .map(|entity| entity.range())
+        .collect()
+}
+
+fn is_addressed_to_bot(msg: &Message, bot_username: &str) -> bool {
+    let is_reply_to_bot = msg
+        .reply_to_message()
+        .and_then(|reply| reply.from())
+        .and_then(|author| author.username.as_deref())
+        .is_some_and(|author| author.eq_ignore_ascii_case(bot_username));
 
-    if message.is_some() {
-        content = msg.text().unwrap();
+    is_reply_to_bot || !bot_mentions(msg, bot_username).is_empty()
+}
+
+/// Message text without the bot mentions, they carry no meaning for GPT.
+fn message_text(msg: &Message, bot_username: &str) -> String {
+    let text = msg.text().unwrap_or_default();
+    let mut stripped = String::with_capacity(text.len());
+    let mut position = 0;
+
+    for mention in bot_mentions(msg, bot_username) {
+        stripped.push_str(&text[position..mention.start]);
+        position = mention.end;
     }
+    stripped.push_str(&text[position..]);
+
+    stripped.trim().to_string()
+}
+
+pub async fn proccess_message(
+    db: &DB,
+    user: &User,
+    bot: Bot,
+    msg: &Message,
+    conversation: Conversation,
+    bot_username: &str,
+) {
+    let content = if let Some(voice) = msg.voice() {
+        match asr(bot.clone(), &voice.file, user.language.as_deref()).await {
+            Ok(transcript) => transcript,
+            Err(error) => {
+                log::error!("ASR error: {}", error);
+                send_thread_message(
+                    bot,
+                    msg.chat.id,
+                    conversation.thread_id,
+                    "I couldn't recognize your voice message",
+                )
+                .await;
+
+                let error_ref: &dyn Error = &*error;
+                sentry::capture_error(error_ref);
+                return;
+            }
+        }
+    } else {
+        message_text(msg, bot_username)
+    };
 
     if content.trim().is_empty() {
         return;
     }
 
+    if user.is_transcribe_only {
+        let message = if msg.voice().is_some() {
+            format!("«{}»", content)
+        } else {
+            "Transcription mode is enabled, send a voice message or use /transcribe to chat again"
+                .to_string()
+        };
+        send_reply(bot, msg.chat.id, msg.id, &message).await;
+        return;
+    }
+
+    if user.is_echo && msg.voice().is_some() {
+        send_reply(bot.clone(), msg.chat.id, msg.id, &format!("«{}»", content)).await;
+    }
+
+    // A shared group history needs the speaker name to tell the members apart.
+    let content = match msg.from() {
+        Some(from) if !msg.chat.is_private() && conversation.member_id == 0 => {
+            format!("{}: {}", from.first_name, content)
+        }
+        _ => content,
+    };
+
     proccess_text_message(TextMessage {
-        user: user,
-        bot: bot,
+        db,
+        user,
+        bot,
         chat_id: msg.chat.id,
-        message: content.clone(),
+        conversation,
+        message: &content,
     })
     .await;
 }
 
-pub async fn on_receive_message(state_users: Vec<User>, bot: Bot, msg: Message) {
-    let user_request = find_user_by_username(&state_users, msg.chat.username().unwrap());
-    let bot_cloned = bot.clone();
-
-    if let Some(user) = user_request {
-        let is_voice_response_required = is_tts_enabled(&user);
+pub async fn on_receive_message(
+    state_users: Vec<User>,
+    bot: Bot,
+    msg: Message,
+    state: Arc<Mutex<State>>,
+    db: DB,
+) {
+    if !msg.chat.is_private() {
+        on_receive_group_message(state_users, bot, msg, state, db).await;
+        return;
+    }
 
-        let typing_interval = set_interval!(
-            move || {
-                if is_voice_response_required {
-                    tokio::spawn(send_voice_recording_action(bot_cloned.clone(), msg.chat.id));
-                } else {
-                    tokio::spawn(send_typing_action(bot_cloned.clone(), msg.chat.id));
-                }
-            },
-            3000
-        );
+    let user_request = authorize_user(&state_users, &msg, &db, &state).await;
+    let bot_username = state.lock().unwrap().bot_username.clone();
 
-        proccess_message(user, bot, &msg).await;
-        clear_timer!(typing_interval);
-        update_chat_id(user, msg.chat.id);
+    if let Some(user) = &user_request {
+        let conversation = conversation_for(&msg, None);
+        respond_to_message(&db, user, bot, &msg, conversation, &bot_username).await;
+        update_chat_id(&db, user, msg.chat.id).await;
     } else {
         send_message(bot, msg.chat.id, "Access denied").await;
     }
 }
 
-fn update_chat_id(user: &User, chat_id: ChatId) {
-    match user.chat_id {
-        Some(_) => {}
-        None => {
-            DATABASE.set_user_chat_id(&user.user_name, chat_id);
+/// The bot stays silent in groups that are not authorized, and in authorized
+/// ones it only answers when mentioned or replied to.
+async fn on_receive_group_message(
+    state_users: Vec<User>,
+    bot: Bot,
+    msg: Message,
+    state: Arc<Mutex<State>>,
+    db: DB,
+) {
+    let group = match find_group(&state, msg.chat.id) {
+        Some(group) => group,
+        None => return,
+    };
+
+    let bot_username = state.lock().unwrap().bot_username.clone();
+    if !is_addressed_to_bot(&msg, &bot_username) {
+        return;
+    }
+
+    if let Some(user) = authorize_group_member(&state_users, &msg, &db, &state).await {
+        let conversation = conversation_for(&msg, Some(&group));
+        respond_to_message(&db, &user, bot, &msg, conversation, &bot_username).await;
+    }
+}
+
+async fn respond_to_message(
+    db: &DB,
+    user: &User,
+    bot: Bot,
+    msg: &Message,
+    conversation: Conversation,
+    bot_username: &str,
+) {
+    let is_voice_response_required = is_tts_enabled(user);
+    let bot_cloned = bot.clone();
+    let chat_id = msg.chat.id;
+    let thread_id = conversation.thread_id;
+
+    let typing_interval = set_interval!(
+        move || {
+            if is_voice_response_required {
+                tokio::spawn(send_voice_recording_action(
+                    bot_cloned.clone(),
+                    chat_id,
+                    thread_id,
+                ));
+            } else {
+                tokio::spawn(send_typing_action(bot_cloned.clone(), chat_id, thread_id));
+            }
+        },
+        3000
+    );
+
+    proccess_message(db, user, bot, msg, conversation, bot_username).await;
+    clear_timer!(typing_interval);
+}
+
+async fn update_chat_id(db: &DB, user: &User, chat_id: ChatId) {
+    if user.chat_id.is_none() {
+        if let Err(err) = db.set_user_chat_id(user.id, chat_id).await {
+            log::error!("Failed to update chat id: {}", err);
+            sentry::capture_error(&err);
         }
     }
 }